@@ -0,0 +1,18 @@
+#!/bin/bash
+# Times examples/fibonacci.ulisp with a larger argument.
+#
+#   $ ./bench.sh [n] [backend] [runs]
+#
+# The program exits with fib(n) truncated to a byte, so the status is ignored.
+n=${1:-35}
+backend=${2:-x86}
+runs=${3:-5}
+
+mkdir -p build/
+sed "s/(fib 8)/(fib $n)/" examples/fibonacci.ulisp > build/fib.ulisp
+cargo run --release -q -- -b "$backend" -o build/fib build/fib.ulisp || exit 1
+
+TIMEFORMAT="%R s"
+for _ in $(seq "$runs"); do
+    time ./build/fib
+done
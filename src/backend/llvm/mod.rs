@@ -1,6 +1,13 @@
-use crate::backend::Backend;
-use crate::parser::Expression;
-use scope::safe_name;
+use crate::backend::{
+    arity_error, assigns, cannot_assign, check_literal, declared_function, defined_twice,
+    definition_kind, expect_number, expect_type, expected_integer, forward_references,
+    lambda_params, loop_outside_tail_position, not_a_definition, operands, promoted_type, run_tool,
+    split_annotation, split_conditional, split_dotimes, split_global, split_let, split_named_let,
+    split_set, untyped_integer, zero, Arity, Backend, Binding, CompileResult, Constant, Definition,
+    LetKind, NamedLet, Param, Signature, Tail, Type, Value, CONDITIONALS, TAIL_FORMS, UNASSIGNABLE,
+};
+use crate::diagnostics::Diagnostic;
+use crate::parser::{Expression, ExpressionKind, IntType, Span};
 use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
@@ -10,12 +17,55 @@ use std::rc::Rc;
 pub use scope::Scope;
 
 mod scope;
+#[cfg(test)]
+mod tests;
 
-type PrimitiveFunction = Rc<Fn(&mut LLVM, &[Expression], Option<&str>, &mut Scope) -> ()>;
+type PrimitiveFunction =
+    Rc<dyn Fn(&mut LLVM, &[Expression], Span, Option<&str>, &mut Scope) -> CompileResult<Type>>;
 
+// Named let loop, whose body starts over when it calls the loop
+struct Loop {
+    name: String,
+    // Label of the block starting the loop, where the phi nodes of its
+    // bindings are
+    head: String,
+    bindings: Vec<Type>,
+    result: Type,
+    // Values of the bindings on each edge into the head, with the block the
+    // edge comes from
+    incoming: Vec<(Vec<String>, String)>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 struct LLVM {
     output: String,
+    // Module level constants and declarations, emitted before the functions
+    globals: String,
+    // Functions lifted out of lambdas, emitted after those of the program
+    functions: String,
+    strings: usize,
+    lambdas: usize,
+    // Allocas of the function being compiled, which go at the start of its
+    // entry block so that loops don't grow the stack
+    allocas: String,
+    // Label of the basic block code is being emitted to, which phi nodes
+    // name as where a value comes from
+    block: String,
+    // Set while the next expression compiled is in tail position
+    tail: Option<Tail>,
+    // Set once the expression compiled last returned from the function with
+    // a tail call, which ends its block, so nothing is emitted after it
+    returned: bool,
+    // Calling convention of the function being compiled
+    convention: &'static str,
+    // Named let loops being compiled, the innermost last
+    loops: Vec<Loop>,
     primitive_functions: HashMap<String, PrimitiveFunction>,
+    signatures: HashMap<String, Signature>,
+    // Values of the constants defined so far, which initializers can use
+    constants: HashMap<String, Value>,
+    // Name resolution errors, collected so compilation can keep going
+    errors: Vec<Diagnostic>,
 }
 
 impl Backend for LLVM {
@@ -27,17 +77,26 @@ impl Backend for LLVM {
     {
         let mut indent = String::with_capacity(depth);
         for _ in 0..depth {
-            indent.push_str("\t");
+            indent.push('\t');
         }
         let s: String = code.into();
         self.output.push_str(&format!("{}{}\n", indent, s.clone()));
     }
 
-    fn compile(&mut self, ast: &Expression) -> String {
+    fn compile(&mut self, ast: &Expression) -> Result<String, Vec<Diagnostic>> {
         let mut scope = Scope::new();
         let destination = scope.symbol(None);
-        self.compile_expression(ast, Some(&destination), &mut scope);
-        self.output.clone()
+        if let Err(error) = self.compile_expression(ast, Some(&destination), &mut scope) {
+            self.errors.push(*error);
+        }
+        if self.errors.is_empty() {
+            Ok(format!(
+                "{}\n{}{}",
+                self.globals, self.output, self.functions
+            ))
+        } else {
+            Err(self.errors.split_off(0))
+        }
     }
 
     fn compile_expression(
@@ -45,140 +104,304 @@ impl Backend for LLVM {
         arg: &Expression,
         destination: Option<&str>,
         scope: &mut Scope,
-    ) {
-        match arg {
-            Expression::List(_vec) => {
-                let (function, args) = split_function(arg);
-                //let dest = scope.symbol();
-                self.compile_call(&function, args, destination, scope);
-                //self.compile_call(&function, args, Some(&dest), scope);
-                return;
-            }
-            Expression::Symbol(symbol) => {
-                if let Some(name) = scope.get(symbol) {
-                    self.emit(
-                        1,
-                        format!("%{} = add i32 %{}, 0", destination.unwrap(), name),
-                    );
+    ) -> CompileResult<Type> {
+        let tail = self.tail.take();
+        match &arg.kind {
+            ExpressionKind::List(items) if matches!(items.first(), Some(head) if matches!(head.kind, ExpressionKind::List(_))) =>
+            {
+                // The head evaluates to the function to call
+                let function = scope.symbol(None);
+                let ty = self.compile_expression(&items[0], Some(&function), scope)?;
+                return self.compile_indirect_call(
+                    &function,
+                    ty,
+                    &items[1..],
+                    arg.span,
+                    destination,
+                    scope,
+                );
+            }
+            ExpressionKind::List(_vec) => {
+                let (function, args) = split_function(arg)?;
+                self.tail = tail;
+                return self.compile_call(function, args, arg.span, destination, scope);
+            }
+            ExpressionKind::Symbol(symbol) => {
+                if let Some((name, ty)) = self.load_variable(symbol, scope) {
+                    self.emit_copy(destination.unwrap(), &ty, &format!("%{}", name));
+                    return Ok(ty);
+                } else if let (Some(name), Some(signature)) =
+                    (scope.get(symbol), self.signatures.get(symbol).cloned())
+                {
+                    return Ok(self.emit_function_value(destination.unwrap(), &name, signature));
                 } else {
-                    panic!(
-                        "Attempt to reference undefined variable or unsupported literal: {} ",
-                        symbol
+                    self.errors.push(
+                        Diagnostic::error(
+                            "E0101",
+                            format!("attempt to reference undefined variable `{}`", symbol),
+                        )
+                        .with_span(arg.span)
+                        .with_label("not found in this scope")
+                        .with_suggestion(symbol, scope.names()),
                     );
                 };
             }
-            Expression::Integer(int) => {
-                self.emit(1, format!("%{} = add i32 {}, 0", destination.unwrap(), int));
+            ExpressionKind::Integer(int, ty) => {
+                let ty = Type::Int(ty.unwrap_or(IntType::I64));
+                self.emit_copy(destination.unwrap(), &ty, &int.to_string());
+                return Ok(ty);
             }
-            Expression::Float(_float) => {
-                unimplemented!();
+            ExpressionKind::String(string) => {
+                let (constant, length) = self.string_constant(string);
+                self.emit(
+                    1,
+                    format!(
+                        "%{} = getelementptr inbounds [{} x i8], [{} x i8]* @{}, i64 0, i64 0",
+                        destination.unwrap(),
+                        length,
+                        length,
+                        constant
+                    ),
+                );
+                return Ok(Type::Str);
             }
-            Expression::Boolean(_boolean) => {
-                unimplemented!();
+            ExpressionKind::Float(float) => {
+                // Hexadecimal is the only exact notation llvm accepts for
+                // every double
+                let literal = format!("0x{:016X}", float.to_bits());
+                self.emit_copy(destination.unwrap(), &Type::Float, &literal);
+                return Ok(Type::Float);
+            }
+            ExpressionKind::Boolean(boolean) => {
+                self.emit_copy(destination.unwrap(), &Type::Bool, &boolean.to_string());
+                return Ok(Type::Bool);
             }
         }
+        Ok(Type::DEFAULT)
     }
 
     fn compile_call(
         &mut self,
         function: &str,
         args: &[Expression],
+        span: Span,
         destination: Option<&str>,
         scope: &mut Scope,
-    ) {
+    ) -> CompileResult<Type> {
+        let tail = self.tail.take();
+        if let Some(index) = self.loops.iter().rposition(|l| l.name == function) {
+            if tail != Some(Tail::Loop(function.to_string())) {
+                return Err(loop_outside_tail_position(function, span).into());
+            }
+            return self.compile_loop_jump(index, args, span, destination, scope);
+        }
         if let Some(fun) = self.get_primitive_function(function) {
-            let mut scope = scope;
-            (*fun)(self, args, destination, &mut scope);
-            return;
+            if TAIL_FORMS.contains(&function) {
+                self.tail = tail;
+            }
+            return (*fun)(self, args, span, destination, scope);
+        }
+        if let Some((value, ty)) = self.load_variable(function, scope) {
+            return self.compile_indirect_call(&value, ty, args, span, destination, scope);
         }
 
-        let valid_function = if let Some(f) = scope.get(function) {
-            f
-        } else {
-            panic!("Attempt to call undefined function: {}", function);
-        };
+        let valid_function = scope.get(function);
+        if valid_function.is_none() {
+            self.errors.push(
+                Diagnostic::error(
+                    "E0102",
+                    format!("attempt to call undefined function `{}`", function),
+                )
+                .with_span(span)
+                .with_label("not found in this scope")
+                .with_suggestion(function, scope.names()),
+            );
+        }
+
+        let signature = self.signatures.get(function).cloned();
+        if let Some(signature) = &signature {
+            if args.len() != signature.params.len() {
+                let expected = signature.params.len().to_string();
+                return Err(arity_error(function, &expected, args.len(), span).into());
+            }
+        }
 
         let safe_args = args
             .iter()
-            .map(|arg| {
+            .enumerate()
+            .map(|(i, arg)| {
                 let sym = scope.symbol(None);
-                self.compile_expression(arg, Some(&sym), scope);
-                format!("i32 %{}", sym)
+                let ty = match &signature {
+                    Some(signature) => {
+                        self.compile_typed(arg, &sym, &signature.params[i], scope)?
+                    }
+                    None => self.compile_expression(arg, Some(&sym), scope)?,
+                };
+                Ok(format!("{} %{}", llvm_type(&ty), sym))
             })
-            .fold("".to_string(), |acc, s| {
-                if acc == "" {
-                    s.to_string()
-                } else {
-                    format!("{}, {}", acc, s)
-                }
-            });
+            .collect::<CompileResult<Vec<String>>>()?
+            .join(", ");
 
-        self.emit(
-            1,
-            format!(
-                "%{} = call i32 @{}({})",
-                destination.unwrap(),
-                valid_function,
-                safe_args
-            ),
-        );
+        let result = signature
+            .as_ref()
+            .map_or(Type::DEFAULT, |signature| signature.result.clone());
+        // A call in tail position returning what the caller does returns
+        // right away, reusing the frame of the caller. With the same params
+        // and calling convention too that's guaranteed, whatever the
+        // optimization level.
+        let callee_convention = convention(function);
+        let call = match (&tail, &signature) {
+            (Some(Tail::Function(caller)), Some(callee))
+                if caller == callee && self.convention == callee_convention =>
+            {
+                "musttail call"
+            }
+            (Some(Tail::Function(caller)), _) if caller.result == result => "tail call",
+            _ => "call",
+        };
+        if let Some(valid_function) = valid_function {
+            self.emit(
+                1,
+                format!(
+                    "%{} = {} {} {} @{}({})",
+                    destination.unwrap(),
+                    call,
+                    callee_convention,
+                    llvm_type(&result),
+                    valid_function,
+                    safe_args
+                ),
+            );
+        }
+        if call != "call" {
+            self.emit(
+                1,
+                format!("ret {} %{}", llvm_type(&result), destination.unwrap()),
+            );
+            self.returned = true;
+        }
+        Ok(result)
     }
 
     fn compile_define(
         &mut self,
         args: &[Expression],
+        span: Span,
         _destination: Option<&str>,
         scope: &mut Scope,
-    ) {
-        let (name, params, body) = split_def_expression(args);
-        // Add this function to outer scope
-        let safe_name = scope.register(name);
+    ) -> CompileResult<Type> {
+        let (name, result, params, body) = split_def_expression(args, span)?;
+        let signature = Signature {
+            params: params.iter().map(|(_, ty)| ty.clone()).collect(),
+            result: result.clone(),
+        };
+        self.signatures.insert(name.clone(), signature.clone());
+        self.convention = convention(&name);
+        // Add this function to outer scope, unless the module already did
+        let safe_name = match scope.get(&name) {
+            Some(safe_name) => safe_name,
+            None => scope.register(name),
+        };
         // Copy outer scope so parameter mappings aren't exposed in outer scope.
         let mut child_scope = scope.copy();
 
-        let safe_params = params
+        let safe_params: Vec<String> = params
             .iter()
-            .map(|param| {
-                if let Expression::Symbol(param_name) = param {
-                    child_scope.register(param_name.to_string())
-                } else {
-                    panic!("")
-                }
-            })
-            .fold("".to_string(), |acc, s| {
-                if acc == "" {
-                    format!("i32 %{}", s)
-                } else {
-                    format!("{}, i32 %{}", acc, s)
-                }
-            });
+            .map(|(param, ty)| child_scope.register_variable(param.to_string(), ty.clone()))
+            .collect();
 
-        self.emit(0, format!("define i32 @{}({}) {{", safe_name, safe_params));
+        self.emit(
+            0,
+            format!(
+                "define {} {} @{}({}) {{",
+                self.convention,
+                llvm_type(&result),
+                safe_name,
+                params
+                    .iter()
+                    .zip(&safe_params)
+                    .map(|((_, ty), safe_param)| format!("{} %{}", llvm_type(ty), safe_param))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        );
 
+        let entry = child_scope.symbol(Some("entry"));
+        self.emit_label(&entry);
+        let entry_position = self.output.len();
+        for ((param, ty), safe_param) in params.iter().zip(safe_params) {
+            self.bind_local(&mut child_scope, param, safe_param, ty.clone(), body);
+        }
         let ret = child_scope.symbol(None);
-        self.compile_expression(body, Some(&ret), &mut child_scope);
+        let (last, effects) = body.split_last().unwrap();
+        self.compile_effects(effects, &mut child_scope)?;
+        self.tail = Some(Tail::Function(signature));
+        let compiled = self.compile_typed(last, &ret, &result, &mut child_scope);
+        // Left over when the last expression was a literal
+        self.tail = None;
+        let returned = std::mem::take(&mut self.returned);
+        compiled?;
 
-        self.emit(1, format!("ret i32 %{}", ret));
+        if !returned {
+            self.emit(1, format!("ret {} %{}", llvm_type(&result), ret));
+        }
         self.emit(0, "}\n");
+        let allocas = std::mem::take(&mut self.allocas);
+        self.output.insert_str(entry_position, &allocas);
+        Ok(Type::DEFAULT)
     }
 
     fn compile_module(
         &mut self,
         args: &[Expression],
+        _span: Span,
         _destination: Option<&str>,
         scope: &mut Scope,
-    ) {
+    ) -> CompileResult<Type> {
+        // Functions can be called before the form defining them
+        for expression in args {
+            if let Some((name, signature)) = declared_function(expression) {
+                if scope.get(&name).is_none() {
+                    scope.register(name.clone());
+                }
+                self.signatures.insert(name, signature);
+            }
+        }
+        // So can globals, whose values are known at compile time
+        for expression in args {
+            if let (Some(kind), ExpressionKind::List(items)) =
+                (definition_kind(expression), &expression.kind)
+            {
+                if kind != "def" {
+                    if let Err(error) =
+                        self.compile_global(kind, &items[1..], expression.span, scope)
+                    {
+                        self.errors.push(*error);
+                    }
+                }
+            }
+        }
+
+        // A broken form doesn't stop the rest of the module from being checked
         for expression in args {
-            self.compile_expression(expression, None, scope);
+            let compiled = match definition_kind(expression) {
+                Some("def") => self.compile_expression(expression, None, scope).map(|_| ()),
+                Some(_) => Ok(()),
+                None => Err(not_a_definition(expression.span).into()),
+            };
+            if let Err(error) = compiled {
+                self.errors.push(*error);
+            }
         }
+        Ok(Type::DEFAULT)
     }
 
-    fn build(&mut self, asm: String, input: &str, output: &str) {
+    fn build(&mut self, asm: String, input: &str, output: &str) -> CompileResult {
         let asmfile = &format!("{}.ll", input);
-        self.write_asm(asmfile, asm);
+        self.write_asm(asmfile, asm)?;
 
-        let objfile = self.run_assembler(asmfile, &input);
-        self.run_linker(&objfile, &output);
+        let objfile = self.run_assembler(asmfile, input)?;
+        self.run_linker(&objfile, output)
     }
 }
 
@@ -188,11 +411,29 @@ impl LLVM {
             let mut m = HashMap::<String, PrimitiveFunction>::new();
             m.insert("def".to_string(), Rc::new(Self::compile_define));
             m.insert("module".to_string(), Rc::new(Self::compile_module));
-            m.insert("+".to_string(), Self::compile_operation("add"));
-            m.insert("-".to_string(), Self::compile_operation("sub"));
-            m.insert("*".to_string(), Self::compile_operation("mul"));
-            m.insert("<".to_string(), Self::compile_operation("icmp slt"));
-            m.insert("if".to_string(), Self::compile_if());
+            for (name, operation) in OPERATIONS {
+                m.insert(name.to_string(), Self::compile_operation(name, *operation));
+            }
+            for name in &["and", "or"] {
+                let (_, operation) = OPERATIONS.iter().find(|(op, _)| op == name).unwrap();
+                m.insert(name.to_string(), Self::compile_logic(name, *operation));
+            }
+            for name in CONDITIONALS {
+                m.insert(name.to_string(), Self::compile_conditional(name));
+            }
+            for name in &["begin", "progn"] {
+                m.insert(name.to_string(), Self::compile_begin(name));
+            }
+            for kind in &[LetKind::Parallel, LetKind::Sequential, LetKind::Recursive] {
+                m.insert(kind.name().to_string(), Self::compile_let(*kind));
+            }
+            m.insert("print".to_string(), Self::compile_print());
+            m.insert("while".to_string(), Self::compile_while());
+            m.insert("set!".to_string(), Self::compile_set());
+            m.insert("dotimes".to_string(), Self::compile_dotimes());
+            for name in &["lambda", "fn"] {
+                m.insert(name.to_string(), Self::compile_lambda(name));
+            }
             m
         };
         let output = String::new();
@@ -200,61 +441,567 @@ impl LLVM {
         LLVM {
             primitive_functions,
             output,
+            globals: String::new(),
+            functions: String::new(),
+            strings: 0,
+            lambdas: 0,
+            allocas: String::new(),
+            block: String::new(),
+            tail: None,
+            returned: false,
+            convention: convention("main"),
+            loops: Vec::new(),
+            signatures: HashMap::new(),
+            constants: HashMap::new(),
+            errors: Vec::new(),
         }
     }
 
-    fn get_primitive_function(&mut self, name: &str) -> Option<PrimitiveFunction> {
-        match self.primitive_functions.get(name) {
-            Some(pf) => Some(pf.clone()),
-            None => None,
+    // Global variable or constant of the module, which functions load from
+    // and store to
+    fn compile_global(
+        &mut self,
+        kind: &str,
+        args: &[Expression],
+        span: Span,
+        scope: &mut Scope,
+    ) -> CompileResult {
+        let global = split_global(kind, args, span, &self.constants)?;
+        if scope.get(global.name).is_some() {
+            return Err(defined_twice(global.name, args[0].span).into());
+        }
+        let name =
+            scope.register_global(global.name.to_string(), global.ty.clone(), global.constant);
+        let initializer = match &global.value {
+            Value::Int(int, _) => (*int as i64).to_string(),
+            Value::Float(float) => format!("0x{:016X}", float.to_bits()),
+            Value::Bool(boolean) => boolean.to_string(),
+            Value::Str(string) => {
+                let (constant, length) = self.string_constant(string);
+                format!(
+                    "getelementptr inbounds ([{} x i8], [{} x i8]* @{}, i64 0, i64 0)",
+                    length, length, constant
+                )
+            }
+        };
+        let linkage = if global.constant {
+            "constant"
+        } else {
+            "global"
+        };
+        self.declare(&format!(
+            "@{} = {} {} {}",
+            name,
+            linkage,
+            llvm_type(&global.ty),
+            initializer
+        ));
+        if global.constant {
+            self.constants.insert(global.name.to_string(), global.value);
         }
+        Ok(())
     }
 
-    fn compile_operation<T: 'static>(operation: T) -> PrimitiveFunction
-    where
-        T: Into<String> + Clone,
-    {
+    // Name holding the current value of the variable `local`, loaded from
+    // memory when it's kept there
+    fn load_variable(&mut self, local: &str, scope: &mut Scope) -> Option<(String, Type)> {
+        let (name, ty) = scope.variable(local)?;
+        let slot = match scope.slot(local) {
+            Some(slot) => slot.to_string(),
+            None => return Some((name, ty)),
+        };
+        let value = scope.symbol(None);
+        let llvm_ty = llvm_type(&ty);
+        self.emit(
+            1,
+            format!("%{} = load {}, {}* {}", value, llvm_ty, llvm_ty, slot),
+        );
+        Some((value, ty))
+    }
+
+    // Binds `local` to `value`, copied to a stack slot of its own when the
+    // expressions in its scope assign it
+    fn bind_local(
+        &mut self,
+        scope: &mut Scope,
+        local: &str,
+        value: String,
+        ty: Type,
+        expressions: &[Expression],
+    ) {
+        if !assigns(expressions, local) {
+            scope.bind_variable(local.to_string(), value, ty);
+            return;
+        }
+        let slot = scope.register_slot(local.to_string(), ty.clone());
+        let llvm_ty = llvm_type(&ty);
+        self.allocas
+            .push_str(&format!("\t%{} = alloca {}\n", slot, llvm_ty));
+        self.emit(
+            1,
+            format!("store {} %{}, {}* %{}", llvm_ty, value, llvm_ty, slot),
+        );
+    }
+
+    // Starts the basic block `label`
+    fn emit_label(&mut self, label: &str) {
+        self.emit(0, format!("{}:", label));
+        self.block = label.to_string();
+    }
+
+    // Adds a module level declaration unless it's already there
+    fn declare(&mut self, declaration: &str) {
+        if !self.globals.lines().any(|line| line == declaration) {
+            self.globals.push_str(&format!("{}\n", declaration));
+        }
+    }
+
+    // Emits a private global holding `value` as a null terminated string,
+    // returning its name and length
+    fn string_constant(&mut self, value: &str) -> (String, usize) {
+        let name = format!(".str.{}", self.strings);
+        self.strings += 1;
+
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        let literal: String = bytes
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() && b != b'"' && b != b'\\' || b == b' ' {
+                    (b as char).to_string()
+                } else {
+                    format!("\\{:02X}", b)
+                }
+            })
+            .collect();
+
+        self.declare(&format!(
+            "@{} = private unnamed_addr constant [{} x i8] c\"{}\", align 1",
+            name,
+            bytes.len(),
+            literal
+        ));
+        (name, bytes.len())
+    }
+
+    // Copies `value` into the `destination` register
+    fn emit_copy(&mut self, destination: &str, ty: &Type, value: &str) {
+        let code = match ty {
+            Type::Int(_) | Type::Bool => {
+                format!("%{} = add {} {}, 0", destination, llvm_type(ty), value)
+            }
+            Type::Float => format!("%{} = bitcast double {} to double", destination, value),
+            Type::Str | Type::Function(_) => {
+                format!("%{} = bitcast i8* {} to i8*", destination, value)
+            }
+        };
+        self.emit(1, code);
+    }
+
+    // Converts the `value` register from type `from` to `to`, returning the
+    // register holding the result
+    fn emit_conversion(
+        &mut self,
+        scope: &mut Scope,
+        value: &str,
+        from: &Type,
+        to: &Type,
+    ) -> String {
+        match (from, to) {
+            (Type::Int(int), Type::Float) => {
+                let converted = scope.symbol(None);
+                let conversion = if int.is_signed() { "sitofp" } else { "uitofp" };
+                self.emit(
+                    1,
+                    format!(
+                        "%{} = {} {} %{} to double",
+                        converted,
+                        conversion,
+                        llvm_type(from),
+                        value
+                    ),
+                );
+                converted
+            }
+            _ => value.to_string(),
+        }
+    }
+
+    // Compiles `expression`, giving an untyped integer literal the type `hint`
+    // when that's an integer type
+    fn compile_hinted(
+        &mut self,
+        expression: &Expression,
+        destination: &str,
+        hint: &Type,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        match (untyped_integer(expression), hint) {
+            (Some(value), Type::Int(int)) => {
+                check_literal(value, *int, expression.span)?;
+                self.emit_copy(destination, hint, &value.to_string());
+                Ok(hint.clone())
+            }
+            _ => self.compile_expression(expression, Some(destination), scope),
+        }
+    }
+
+    // Compiles `expression` where a value of type `expected` is needed. Bools
+    // are widened to 0 or 1 where an integer is expected.
+    fn compile_typed(
+        &mut self,
+        expression: &Expression,
+        destination: &str,
+        expected: &Type,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let value = scope.symbol(None);
+        let ty = self.compile_hinted(expression, &value, expected, scope)?;
+        let widened = matches!((&ty, expected), (Type::Bool, Type::Int(_)));
+        if !widened {
+            expect_type(expected, &ty, expression.span)?;
+        }
+        // A tail call has returned the value already
+        if self.returned {
+            return Ok(expected.clone());
+        }
+        if widened {
+            self.emit(
+                1,
+                format!(
+                    "%{} = zext i1 %{} to {}",
+                    destination,
+                    value,
+                    llvm_type(expected)
+                ),
+            );
+        } else {
+            self.emit_copy(destination, &ty, &format!("%{}", value));
+        }
+        Ok(expected.clone())
+    }
+
+    fn get_primitive_function(&mut self, name: &str) -> Option<PrimitiveFunction> {
+        self.primitive_functions.get(name).cloned()
+    }
+
+    // Operation described by `operation`, applied to its operands as its
+    // arity says. A `-` with a single operand negates it.
+    fn compile_operation(name: &'static str, operation: Operation) -> PrimitiveFunction {
         let c = move |backend: &mut LLVM,
                       expressions: &[Expression],
+                      span: Span,
                       destination: Option<&str>,
                       scope: &mut Scope| {
-            let exp1 = &expressions[0];
-            let exp2 = &expressions[1];
+            if name == "-" && expressions.len() == 1 {
+                return backend.compile_negation(&expressions[0], destination.unwrap(), scope);
+            }
+            let operands = operands(name, operation.arity, expressions, span)?;
+            let values = backend.compile_operands(&operands, scope)?;
+            let destination = destination.unwrap();
 
-            let arg1 = scope.symbol(None);
-            let arg2 = scope.symbol(None);
+            if let Arity::Chain = operation.arity {
+                // Every adjacent pair must compare true
+                if values.len() == 1 {
+                    let (_, ty) = &values[0];
+                    operand_type(name, &operation, ty, ty, span)?;
+                    backend.emit_copy(destination, &Type::Bool, "true");
+                }
+                let mut conjunction: Option<String> = None;
+                for (i, pair) in values.windows(2).enumerate() {
+                    let last = i + 2 == values.len();
+                    let comparison = match (&conjunction, last) {
+                        (None, true) => destination.to_string(),
+                        _ => scope.symbol(None),
+                    };
+                    backend.emit_step(
+                        name,
+                        &operation,
+                        &pair[0],
+                        &pair[1],
+                        &comparison,
+                        span,
+                        scope,
+                    )?;
+                    conjunction = Some(match conjunction {
+                        None => comparison,
+                        Some(previous) => {
+                            let result = if last {
+                                destination.to_string()
+                            } else {
+                                scope.symbol(None)
+                            };
+                            backend.emit(
+                                1,
+                                format!("%{} = and i1 %{}, %{}", result, previous, comparison),
+                            );
+                            result
+                        }
+                    });
+                }
+                return Ok(Type::Bool);
+            }
 
-            backend.compile_expression(exp1, Some(&arg1), scope);
-            backend.compile_expression(exp2, Some(&arg2), scope);
-            backend.emit(
+            let (first, ty) = &values[0];
+            if values.len() == 1 {
+                operand_type(name, &operation, ty, ty, span)?;
+                backend.emit_copy(destination, ty, &format!("%{}", first));
+                return Ok(ty.clone());
+            }
+            let mut accumulator = values[0].clone();
+            for (i, value) in values.iter().enumerate().skip(1) {
+                let result = if i + 1 == values.len() {
+                    destination.to_string()
+                } else {
+                    scope.symbol(None)
+                };
+                let ty = backend.emit_step(
+                    name,
+                    &operation,
+                    &accumulator,
+                    value,
+                    &result,
+                    span,
+                    scope,
+                )?;
+                accumulator = (result, ty);
+            }
+            Ok(accumulator.1)
+        };
+        Rc::new(c)
+    }
+
+    // Compiles the operands of an operation into registers. Untyped integer
+    // literals take the type of the first other operand, so they are compiled
+    // last; they have no side effects to reorder.
+    fn compile_operands(
+        &mut self,
+        operands: &[Expression],
+        scope: &mut Scope,
+    ) -> CompileResult<Vec<(String, Type)>> {
+        let mut values = vec![None; operands.len()];
+        let mut hint = None;
+        for (i, operand) in operands.iter().enumerate() {
+            if untyped_integer(operand).is_none() {
+                let register = scope.symbol(None);
+                let ty = self.compile_expression(operand, Some(&register), scope)?;
+                hint.get_or_insert(ty.clone());
+                values[i] = Some((register, ty));
+            }
+        }
+        for (i, operand) in operands.iter().enumerate() {
+            if values[i].is_none() {
+                let register = scope.symbol(None);
+                let hint = hint.as_ref().unwrap_or(&Type::DEFAULT);
+                let ty = self.compile_hinted(operand, &register, hint, scope)?;
+                values[i] = Some((register, ty));
+            }
+        }
+        Ok(values.into_iter().flatten().collect())
+    }
+
+    // Applies a binary operation to two compiled operands, converting them to
+    // a common type first, and returns the type of the result
+    #[allow(clippy::too_many_arguments)]
+    fn emit_step(
+        &mut self,
+        name: &str,
+        operation: &Operation,
+        (arg1, ty1): &(String, Type),
+        (arg2, ty2): &(String, Type),
+        destination: &str,
+        span: Span,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let ty = operand_type(name, operation, ty1, ty2, span)?;
+        let arg1 = self.emit_conversion(scope, arg1, ty1, &ty);
+        let arg2 = self.emit_conversion(scope, arg2, ty2, &ty);
+        self.emit_operation(operation, &ty, destination, &arg1, &arg2, scope);
+        Ok(if let OperationKind::Comparison = operation.kind {
+            Type::Bool
+        } else {
+            ty
+        })
+    }
+
+    // Emits `operation` on two registers of type `ty`
+    fn emit_operation(
+        &mut self,
+        operation: &Operation,
+        ty: &Type,
+        destination: &str,
+        arg1: &str,
+        arg2: &str,
+        scope: &mut Scope,
+    ) {
+        let instruction = operation.instruction(ty).unwrap();
+        let llvm_ty = llvm_type(ty);
+
+        let mut arg2 = arg2.to_string();
+        match (operation.kind, ty) {
+            (OperationKind::Division, Type::Int(_)) | (OperationKind::Modulo, Type::Int(_)) => {
+                self.emit_zero_check(llvm_ty, &arg2, scope);
+            }
+            // Shift amounts are taken modulo the width of the operand, as
+            // x86 does, rather than producing a poison value
+            (OperationKind::Shift, Type::Int(int)) => {
+                let masked = scope.symbol(None);
+                self.emit(
+                    1,
+                    format!(
+                        "%{} = and {} %{}, {}",
+                        masked,
+                        llvm_ty,
+                        arg2,
+                        int.bits() - 1
+                    ),
+                );
+                arg2 = masked;
+            }
+            _ => {}
+        }
+
+        if operation.kind != OperationKind::Modulo || *ty == Type::Int(IntType::U64) {
+            self.emit(
                 1,
                 format!(
-                    "%{} = {} i32 %{}, %{}",
-                    destination.unwrap(),
-                    operation.clone().into(),
-                    arg1,
-                    arg2
+                    "%{} = {} {} %{}, %{}",
+                    destination, instruction, llvm_ty, arg1, arg2
+                ),
+            );
+            return;
+        }
+
+        // A non zero remainder with a sign different from the divisor's is
+        // moved into the divisor's range, e.g. (mod -7 2) is 1 and not -1
+        let remainder = scope.symbol(None);
+        let negative = scope.symbol(None);
+        let non_zero = scope.symbol(None);
+        let adjust = scope.symbol(None);
+        let adjusted = scope.symbol(None);
+        self.emit(
+            1,
+            format!(
+                "%{} = {} {} %{}, %{}",
+                remainder, instruction, llvm_ty, arg1, arg2
+            ),
+        );
+        if *ty == Type::Float {
+            let remainder_negative = scope.symbol(None);
+            let divisor_negative = scope.symbol(None);
+            self.emit(
+                1,
+                format!(
+                    "%{} = fcmp olt double %{}, 0.0",
+                    remainder_negative, remainder
+                ),
+            );
+            self.emit(
+                1,
+                format!("%{} = fcmp olt double %{}, 0.0", divisor_negative, arg2),
+            );
+            self.emit(
+                1,
+                format!(
+                    "%{} = xor i1 %{}, %{}",
+                    negative, remainder_negative, divisor_negative
                 ),
             );
+            self.emit(
+                1,
+                format!("%{} = fcmp une double %{}, 0.0", non_zero, remainder),
+            );
+            self.emit(
+                1,
+                format!("%{} = fadd double %{}, %{}", adjusted, remainder, arg2),
+            );
+        } else {
+            let signs = scope.symbol(None);
+            self.emit(
+                1,
+                format!("%{} = xor {} %{}, %{}", signs, llvm_ty, remainder, arg2),
+            );
+            self.emit(
+                1,
+                format!("%{} = icmp slt {} %{}, 0", negative, llvm_ty, signs),
+            );
+            self.emit(
+                1,
+                format!("%{} = icmp ne {} %{}, 0", non_zero, llvm_ty, remainder),
+            );
+            self.emit(
+                1,
+                format!("%{} = add {} %{}, %{}", adjusted, llvm_ty, remainder, arg2),
+            );
+        }
+        self.emit(
+            1,
+            format!("%{} = and i1 %{}, %{}", adjust, negative, non_zero),
+        );
+        self.emit(
+            1,
+            format!(
+                "%{} = select i1 %{}, {} %{}, {} %{}",
+                destination, adjust, llvm_ty, adjusted, llvm_ty, remainder
+            ),
+        );
+    }
+
+    // Traps when the integer `divisor` register is zero
+    fn emit_zero_check(&mut self, llvm_ty: &str, divisor: &str, scope: &mut Scope) {
+        self.declare("declare void @llvm.trap() noreturn nounwind");
+        let is_zero = scope.symbol(None);
+        let zero_label = scope.symbol(Some("divzero"));
+        let ok_label = scope.symbol(Some("divok"));
+        self.emit(
+            1,
+            format!("%{} = icmp eq {} %{}, 0", is_zero, llvm_ty, divisor),
+        );
+        self.emit(
+            1,
+            format!(
+                "br i1 %{}, label %{}, label %{}",
+                is_zero, zero_label, ok_label
+            ),
+        );
+        self.emit_label(&zero_label);
+        self.emit(1, "call void @llvm.trap()");
+        self.emit(1, "unreachable");
+        self.emit_label(&ok_label);
+    }
+
+    fn compile_negation(
+        &mut self,
+        expression: &Expression,
+        destination: &str,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let value = scope.symbol(None);
+        let ty = self.compile_expression(expression, Some(&value), scope)?;
+        expect_number(&ty, expression.span)?;
+        let code = match ty {
+            Type::Float => format!("%{} = fneg double %{}", destination, value),
+            _ => format!("%{} = sub {} 0, %{}", destination, llvm_type(&ty), value),
         };
-        Rc::new(c)
+        self.emit(1, code);
+        Ok(ty)
     }
 
-    fn compile_if() -> PrimitiveFunction {
+    // Evaluates one of the bodies of a conditional form depending on its
+    // test, the one compiled first giving the type of the other
+    fn compile_conditional(name: &'static str) -> PrimitiveFunction {
         let c = move |backend: &mut LLVM,
                       expressions: &[Expression],
+                      span: Span,
                       destination: Option<&str>,
                       scope: &mut Scope| {
+            let conditional = split_conditional(name, expressions, span)?;
+            let tail = backend.tail.take();
             let test_var = scope.symbol(None);
-            let result = scope.symbol(Some("ifresult"));
-            // Space for result
-            backend.emit(1, format!("%{} = alloca i32, align 4", result));
 
-            let test = &expressions[0];
-            let then_block = &expressions[1];
-            let else_block = &expressions[2];
-
-            backend.compile_expression(test, Some(&test_var), scope);
+            let test = &conditional.test;
+            let test_ty = backend.compile_expression(test, Some(&test_var), scope)?;
+            expect_type(&Type::Bool, &test_ty, test.span)?;
             let true_label = scope.symbol(Some("iftrue"));
             let false_label = scope.symbol(Some("iffalse"));
 
@@ -265,97 +1012,1330 @@ impl LLVM {
                     test_var, true_label, false_label
                 ),
             );
+            let (first, second, negated) = conditional.bodies();
+            let (first_label, second_label) = if negated {
+                (false_label, true_label)
+            } else {
+                (true_label, false_label)
+            };
 
-            // Compile true section
-            backend.emit(0, format!("{}:", true_label));
-            let tmp1 = scope.symbol(None);
-            backend.compile_expression(then_block, Some(&tmp1), scope);
-            backend.emit(1, format!("store i32 %{}, i32* %{}, align 4", tmp1, result));
-
+            // Compile the body present. Bodies ending with a tail call have
+            // returned, the others branch to the end with their value.
             let end_label = scope.symbol(Some("ifend"));
-            backend.emit(1, format!("br label %{}", end_label));
-            backend.emit(0, format!("{}:", false_label));
+            let mut incoming = Vec::new();
+            backend.emit_label(&first_label);
+            let tmp1 = scope.symbol(None);
+            backend.tail = tail.clone();
+            let ty = backend.compile_body(first, &tmp1, None, scope)?;
+            if !std::mem::take(&mut backend.returned) {
+                // Nested branches leave the body in a block of their own
+                incoming.push(format!("[%{}, %{}]", tmp1, backend.block));
+                backend.emit(1, format!("br label %{}", end_label));
+            }
+            backend.emit_label(&second_label);
 
-            // Compile false section
+            // Compile the other one, or its zero value when missing
             let tmp2 = scope.symbol(None);
-            backend.compile_expression(else_block, Some(&tmp2), scope);
-            backend.emit(1, format!("store i32 %{}, i32* %{}, align 4", tmp2, result));
-            backend.emit(1, format!("br label %{}", end_label));
+            backend.tail = tail;
+            if second.is_empty() {
+                backend.compile_typed(&zero(&ty, span)?, &tmp2, &ty, scope)?;
+            } else {
+                backend.compile_body(second, &tmp2, Some(ty.clone()), scope)?;
+            }
+            if !std::mem::take(&mut backend.returned) {
+                incoming.push(format!("[%{}, %{}]", tmp2, backend.block));
+                backend.emit(1, format!("br label %{}", end_label));
+            }
 
-            // Clean up
-            backend.emit(0, format!("{}:", end_label));
+            // The value comes from whichever body got to the end, if any did
+            if incoming.is_empty() {
+                backend.returned = true;
+                return Ok(ty);
+            }
+            backend.emit_label(&end_label);
             backend.emit(
                 1,
                 format!(
-                    "%{} = load i32, i32* %{}, align 4",
+                    "%{} = phi {} {}",
                     destination.unwrap(),
-                    result
+                    llvm_type(&ty),
+                    incoming.join(", ")
                 ),
             );
+            Ok(ty)
         };
         Rc::new(c)
     }
 
-    fn write_asm(&mut self, output: &str, asm: String) {
-        let mut output = fs::File::create(output).expect("failed open output file");
-        output
-            .write_all(asm.as_bytes())
-            .expect("failed write output file");
-    }
+    // `and` or `or` of bools, only evaluating operands until one of them
+    // decides the result. Integers are combined bitwise by `operation`.
+    fn compile_logic(name: &'static str, operation: Operation) -> PrimitiveFunction {
+        let bitwise = Self::compile_operation(name, operation);
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let first = match expressions.first() {
+                Some(first) if untyped_integer(first).is_none() => first,
+                _ => return bitwise(backend, expressions, span, destination, scope),
+            };
+            let destination = destination.unwrap();
+            let value = scope.symbol(None);
+            let ty = backend.compile_expression(first, Some(&value), scope)?;
+            if ty != Type::Bool {
+                return backend.fold_bitwise(
+                    name,
+                    &operation,
+                    (value, ty),
+                    expressions,
+                    destination,
+                    scope,
+                );
+            }
 
-    fn run_assembler(&mut self, asmfile: &str, codefile: &str) -> String {
-        let objfile = format!("{}.s", codefile);
-        Command::new("llc")
-            .arg("-o")
-            .arg(&objfile)
-            .arg(asmfile)
-            .output()
-            .expect("failed to run llc");
-        objfile
-    }
-
-    fn run_linker(&mut self, objfile: &str, binary: &str) {
-        Command::new("gcc")
-            .arg("-o")
-            .arg(binary)
-            .arg(objfile)
-            .output()
-            .expect("failed to run gcc");
-    }
+            // Every operand but the last branches to the end when it decides
+            // the result, which is then its own value
+            let decisive = name == "or";
+            let end_label = scope.symbol(Some(&format!("{}end", name)));
+            let mut incoming = Vec::new();
+            let mut value = value;
+            for operand in &expressions[1..] {
+                let next_label = scope.symbol(Some(&format!("{}next", name)));
+                let (on_true, on_false) = if decisive {
+                    (&end_label, &next_label)
+                } else {
+                    (&next_label, &end_label)
+                };
+                backend.emit(
+                    1,
+                    format!("br i1 %{}, label %{}, label %{}", value, on_true, on_false),
+                );
+                incoming.push(format!("[{}, %{}]", decisive, backend.block));
+                backend.emit_label(&next_label);
+                value = scope.symbol(None);
+                backend.compile_typed(operand, &value, &Type::Bool, scope)?;
+            }
+            backend.emit(1, format!("br label %{}", end_label));
+            incoming.push(format!("[%{}, %{}]", value, backend.block));
+            backend.emit_label(&end_label);
+            backend.emit(
+                1,
+                format!("%{} = phi i1 {}", destination, incoming.join(", ")),
+            );
+            Ok(Type::Bool)
+        };
+        Rc::new(c)
+    }
+
+    // Folds integer operands bitwise into the value of the first one, already
+    // compiled. Untyped integer literals take its type.
+    fn fold_bitwise(
+        &mut self,
+        name: &str,
+        operation: &Operation,
+        first: (String, Type),
+        operands: &[Expression],
+        destination: &str,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let span = operands[0].span;
+        let ty = first.1.clone();
+        if operands.len() == 1 {
+            operand_type(name, operation, &ty, &ty, span)?;
+            self.emit_copy(destination, &ty, &format!("%{}", first.0));
+            return Ok(ty);
+        }
+        let mut accumulator = first;
+        for (i, operand) in operands.iter().enumerate().skip(1) {
+            let register = scope.symbol(None);
+            let next = self.compile_hinted(operand, &register, &ty, scope)?;
+            let result = if i + 1 == operands.len() {
+                destination.to_string()
+            } else {
+                scope.symbol(None)
+            };
+            let ty = self.emit_step(
+                name,
+                operation,
+                &accumulator,
+                &(register, next),
+                &result,
+                operand.span,
+                scope,
+            )?;
+            accumulator = (result, ty);
+        }
+        Ok(accumulator.1)
+    }
+
+    // Anonymous function, closed over the variables of the enclosing scope
+    // its body refers to. Its code is lifted to a function of its own taking
+    // the closure, a struct with a pointer to that code and the values
+    // captured, as a first param.
+    fn compile_lambda(name: &'static str) -> PrimitiveFunction {
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            if expressions.len() < 2 {
+                return Err(arity_error(name, "at least 2", expressions.len(), span).into());
+            }
+            let params = split_params(&expressions[0]).ok_or_else(|| {
+                Diagnostic::error("E0104", format!("malformed params in {}", name))
+                    .with_span(expressions[0].span)
+                    .with_label("expected a list of params")
+            })??;
+            let body = &expressions[1..];
+
+            // Everything the body mentions is captured, save the params and
+            // the globals, which the lifted function loads and stores itself.
+            // Of a variable kept in a cell, the pointer to it is.
+            let mut captures: Vec<Capture> = Vec::new();
+            for symbol in body.iter().flat_map(symbols) {
+                let captured = captures.iter().any(|capture| capture.name == symbol)
+                    || params.iter().any(|(param, _)| param == symbol);
+                let global = scope.slot(symbol).is_some_and(|slot| slot.starts_with('@'));
+                if captured || global {
+                    continue;
+                }
+                let cell = scope.cell(symbol).is_some();
+                let variable = if cell {
+                    scope.variable(symbol)
+                } else {
+                    backend.load_variable(symbol, scope)
+                };
+                if let Some((value, ty)) = variable {
+                    captures.push(Capture {
+                        name: symbol.to_string(),
+                        value,
+                        ty,
+                        cell,
+                    });
+                }
+            }
+            let capture_types: Vec<String> = captures.iter().map(Capture::llvm_type).collect();
+            let closure = closure_type(&capture_types);
+
+            // The lifted function is compiled on its own, its result type is
+            // only known once its body is
+            backend.lambdas += 1;
+            let function = format!("lambda.{}", backend.lambdas);
+            let output = std::mem::take(&mut backend.output);
+            let block = std::mem::take(&mut backend.block);
+            let allocas = std::mem::take(&mut backend.allocas);
+            let mut inner = scope.copy();
+            let env = inner.symbol(Some("env"));
+            let mut header = vec![format!("i8* %{}", env)];
+            let safe_params: Vec<String> = params
+                .iter()
+                .map(|(param, ty)| inner.register_variable(param.to_string(), ty.clone()))
+                .collect();
+            for ((_, ty), safe_param) in params.iter().zip(&safe_params) {
+                header.push(format!("{} %{}", llvm_type(ty), safe_param));
+            }
+            let entry = inner.symbol(Some("entry"));
+            backend.emit_label(&entry);
+            let entry_position = backend.output.len();
+            for ((param, ty), safe_param) in params.iter().zip(safe_params) {
+                backend.bind_local(&mut inner, param, safe_param, ty.clone(), body);
+            }
+            let fields = inner.symbol(None);
+            backend.emit(
+                1,
+                format!("%{} = bitcast i8* %{} to {}*", fields, env, closure),
+            );
+            for (i, capture) in captures.iter().enumerate() {
+                let field = inner.symbol(None);
+                let value = inner.symbol(None);
+                backend.emit(
+                    1,
+                    format!(
+                        "%{} = getelementptr {}, {}* %{}, i32 0, i32 {}",
+                        field,
+                        closure,
+                        closure,
+                        fields,
+                        i + 1
+                    ),
+                );
+                let llvm_ty = capture.llvm_type();
+                backend.emit(
+                    1,
+                    format!("%{} = load {}, {}* %{}", value, llvm_ty, llvm_ty, field),
+                );
+                if capture.cell {
+                    inner.bind_cell(capture.name.clone(), value, capture.ty.clone());
+                } else {
+                    inner.bind_variable(capture.name.clone(), value, capture.ty.clone());
+                }
+            }
+            let ret = inner.symbol(None);
+            let (last, effects) = body.split_last().unwrap();
+            let compiled = backend
+                .compile_effects(effects, &mut inner)
+                .and_then(|_| backend.compile_expression(last, Some(&ret), &mut inner));
+            let own_allocas = std::mem::replace(&mut backend.allocas, allocas);
+            let mut code = std::mem::replace(&mut backend.output, output);
+            code.insert_str(entry_position, &own_allocas);
+            backend.block = block;
+            let result = compiled?;
+            backend.functions.push_str(&format!(
+                "define {} @{}({}) {{\n{}\tret {} %{}\n}}\n\n",
+                llvm_type(&result),
+                function,
+                header.join(", "),
+                code,
+                llvm_type(&result),
+                ret
+            ));
+
+            let signature = Signature {
+                params: params.iter().map(|(_, ty)| ty.clone()).collect(),
+                result,
+            };
+            let code = format!(
+                "bitcast ({} @{} to i8*)",
+                closure_function_type(&signature),
+                function
+            );
+            let values: Vec<(String, String)> = captures
+                .iter()
+                .map(|capture| (capture.value.clone(), capture.llvm_type()))
+                .collect();
+            backend.emit_closure(destination.unwrap(), &code, &values, scope);
+            Ok(Type::function(signature))
+        };
+        Rc::new(c)
+    }
+
+    // Allocates a closure with the given code and captured values, of the
+    // given LLVM types
+    fn emit_closure(
+        &mut self,
+        destination: &str,
+        code: &str,
+        captures: &[(String, String)],
+        scope: &mut Scope,
+    ) {
+        let types: Vec<String> = captures.iter().map(|(_, ty)| ty.clone()).collect();
+        let closure = closure_type(&types);
+        self.emit_malloc(destination, &closure, scope);
+        let fields = scope.symbol(None);
+        self.emit(
+            1,
+            format!("%{} = bitcast i8* %{} to {}*", fields, destination, closure),
+        );
+        let mut values = vec![(code.to_string(), "i8*")];
+        values.extend(
+            captures
+                .iter()
+                .map(|(value, ty)| (format!("%{}", value), ty.as_str())),
+        );
+        for (i, (value, ty)) in values.iter().enumerate() {
+            let field = scope.symbol(None);
+            self.emit(
+                1,
+                format!(
+                    "%{} = getelementptr {}, {}* %{}, i32 0, i32 {}",
+                    field, closure, closure, fields, i
+                ),
+            );
+            self.emit(1, format!("store {} {}, {}* %{}", ty, value, ty, field));
+        }
+    }
+
+    // Allocates room on the heap for a value of the LLVM type `ty`
+    fn emit_malloc(&mut self, destination: &str, ty: &str, scope: &mut Scope) {
+        self.declare("declare i8* @malloc(i64)");
+        let end = scope.symbol(None);
+        let size = scope.symbol(None);
+        self.emit(
+            1,
+            format!("%{} = getelementptr {}, {}* null, i32 1", end, ty, ty),
+        );
+        self.emit(1, format!("%{} = ptrtoint {}* %{} to i64", size, ty, end));
+        self.emit(
+            1,
+            format!("%{} = call i8* @malloc(i64 %{})", destination, size),
+        );
+    }
+
+    // Function defined by the program used as a value: a closure capturing
+    // nothing, whose code calls the function
+    fn emit_function_value(&mut self, destination: &str, name: &str, signature: Signature) -> Type {
+        // Declared once, however many times the function is used
+        let value = format!("@{}.value", name);
+        if !self.globals.contains(&format!("{} =", value)) {
+            let params: Vec<String> = signature
+                .params
+                .iter()
+                .enumerate()
+                .map(|(i, ty)| format!("{} %p{}", llvm_type(ty), i))
+                .collect();
+            let result = llvm_type(&signature.result);
+            self.declare(&format!(
+                "define {} @{}.closure(i8* %env{}) {{\n\t%result = call {} {} @{}({})\n\tret {} %result\n}}",
+                result,
+                name,
+                params.iter().map(|param| format!(", {}", param)).collect::<String>(),
+                convention(name),
+                result,
+                name,
+                params.join(", "),
+                result
+            ));
+            let code = format!(
+                "bitcast ({} @{}.closure to i8*)",
+                closure_function_type(&signature),
+                name
+            );
+            self.declare(&format!(
+                "{} = constant {{ i8* }} {{ i8* {} }}",
+                value, code
+            ));
+        }
+        self.emit(
+            1,
+            format!("%{} = bitcast {{ i8* }}* {} to i8*", destination, value),
+        );
+        Type::function(signature)
+    }
+
+    // Calls the closure in `closure`, of type `ty`, through the code pointer
+    // it starts with
+    fn compile_indirect_call(
+        &mut self,
+        closure: &str,
+        ty: Type,
+        args: &[Expression],
+        span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let signature = match ty {
+            Type::Function(signature) => signature,
+            _ => {
+                return Err(Diagnostic::error("E0109", "mismatched types")
+                    .with_span(span)
+                    .with_label(format!("expected a function, found `{}`", ty))
+                    .into())
+            }
+        };
+        if args.len() != signature.params.len() {
+            let expected = signature.params.len().to_string();
+            return Err(arity_error("function", &expected, args.len(), span).into());
+        }
+        let mut values = vec![format!("i8* %{}", closure)];
+        for (arg, ty) in args.iter().zip(&signature.params) {
+            let value = scope.symbol(None);
+            self.compile_typed(arg, &value, ty, scope)?;
+            values.push(format!("{} %{}", llvm_type(ty), value));
+        }
+
+        let slot = scope.symbol(None);
+        let code = scope.symbol(None);
+        let function = scope.symbol(None);
+        let function_type = closure_function_type(&signature);
+        self.emit(1, format!("%{} = bitcast i8* %{} to i8**", slot, closure));
+        self.emit(1, format!("%{} = load i8*, i8** %{}", code, slot));
+        self.emit(
+            1,
+            format!("%{} = bitcast i8* %{} to {}", function, code, function_type),
+        );
+        self.emit(
+            1,
+            format!(
+                "%{} = call {} %{}({})",
+                destination.unwrap(),
+                llvm_type(&signature.result),
+                function,
+                values.join(", ")
+            ),
+        );
+        Ok(signature.result.clone())
+    }
+
+    // Evaluates expressions in order, the last one giving the value
+    fn compile_begin(name: &'static str) -> PrimitiveFunction {
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| match expressions.split_last() {
+            Some((last, effects)) => {
+                let tail = backend.tail.take();
+                backend.compile_effects(effects, scope)?;
+                backend.tail = tail;
+                backend.compile_expression(last, destination, scope)
+            }
+            None => Err(arity_error(name, "at least 1", 0, span).into()),
+        };
+        Rc::new(c)
+    }
+
+    // Evaluates expressions only for their side effects, ignoring their values
+    fn compile_effects(&mut self, expressions: &[Expression], scope: &mut Scope) -> CompileResult {
+        for expression in expressions {
+            let ignored = scope.symbol(None);
+            self.compile_expression(expression, Some(&ignored), scope)?;
+        }
+        Ok(())
+    }
+
+    // Compiles a body into `destination`, its last expression giving its
+    // value, which must be of type `expected` when given
+    fn compile_body(
+        &mut self,
+        body: &[Expression],
+        destination: &str,
+        expected: Option<Type>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let (last, effects) = body.split_last().unwrap();
+        let tail = self.tail.take();
+        self.compile_effects(effects, scope)?;
+        self.tail = tail;
+        match expected {
+            Some(ty) => self.compile_typed(last, destination, &ty, scope),
+            None => self.compile_expression(last, Some(destination), scope),
+        }
+    }
+
+    // Binds names to values while evaluating a body, computing each value in
+    // the scope `kind` says. Shadowed names get new safe names from `Scope`.
+    fn compile_let(kind: LetKind) -> PrimitiveFunction {
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let tail = backend.tail.take();
+            if kind == LetKind::Parallel {
+                if let Some(named_let) = split_named_let(expressions, span)? {
+                    return backend.compile_named_let(named_let, destination, scope);
+                }
+            }
+            let (bindings, body) = split_let(kind, expressions, span)?;
+            let mut inner = scope.clone();
+            // The letrec bindings lambdas refer to before they're bound are
+            // kept in cells, bound from the start
+            if kind == LetKind::Recursive {
+                let forward = forward_references(&bindings);
+                for binding in bindings.iter().filter(|b| forward.contains(&b.name)) {
+                    let ty = backend.forward_type(binding)?;
+                    let cell = inner.symbol(None);
+                    backend.emit_malloc(&cell, llvm_type(&ty), &mut inner);
+                    let pointer = inner.register_cell(binding.name.to_string(), ty.clone());
+                    backend.emit(
+                        1,
+                        format!(
+                            "%{} = bitcast i8* %{} to {}*",
+                            pointer,
+                            cell,
+                            llvm_type(&ty)
+                        ),
+                    );
+                }
+            }
+            let mut values = Vec::with_capacity(bindings.len());
+            for binding in &bindings {
+                let value_scope = if kind == LetKind::Parallel {
+                    &mut *scope
+                } else {
+                    &mut inner
+                };
+                let value = value_scope.symbol(None);
+                if let Some(pointer) = value_scope.cell(binding.name).map(str::to_string) {
+                    let (_, ty) = value_scope.variable(binding.name).unwrap();
+                    backend.compile_typed(binding.value, &value, &ty, value_scope)?;
+                    let llvm_ty = llvm_type(&ty);
+                    backend.emit(
+                        1,
+                        format!("store {} %{}, {}* %{}", llvm_ty, value, llvm_ty, pointer),
+                    );
+                    continue;
+                }
+                let ty = match &binding.ty {
+                    Some(ty) => backend.compile_typed(binding.value, &value, ty, value_scope)?,
+                    None => backend.compile_expression(binding.value, Some(&value), value_scope)?,
+                };
+                if kind == LetKind::Parallel {
+                    values.push((binding.name, value, ty));
+                } else {
+                    // Assigned in the values of later bindings, or the body
+                    backend.bind_local(&mut inner, binding.name, value, ty, expressions);
+                }
+            }
+            for (name, value, ty) in values {
+                backend.bind_local(&mut inner, name, value, ty, body);
+            }
+            let (last, effects) = body.split_last().unwrap();
+            backend.compile_effects(effects, &mut inner)?;
+            backend.tail = tail;
+            backend.compile_expression(last, destination, &mut inner)
+        };
+        Rc::new(c)
+    }
+
+    // Type of a letrec binding referred to before it's bound: its annotation,
+    // or for a lambda, a function of its params returning the type its body
+    // evidently has, the default type if none
+    fn forward_type(&self, binding: &Binding) -> CompileResult<Type> {
+        if let Some(ty) = &binding.ty {
+            return Ok(ty.clone());
+        }
+        match lambda_params(binding.value).and_then(split_params) {
+            Some(params) => Ok(Type::function(Signature {
+                params: params?.into_iter().map(|(_, ty)| ty).collect(),
+                result: result_hint(binding.value).unwrap_or(Type::DEFAULT),
+            })),
+            None => Ok(Type::DEFAULT),
+        }
+    }
+
+    // Loop whose bindings start with the values given and get those of each
+    // call to the loop in tail position of its body, which jumps back to
+    // its start. The value of the body when it doesn't call the loop is
+    // that of the loop.
+    fn compile_named_let(
+        &mut self,
+        (name, result, bindings, body): NamedLet,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let mut values = Vec::with_capacity(bindings.len());
+        let mut types = Vec::with_capacity(bindings.len());
+        for binding in &bindings {
+            let value = scope.symbol(None);
+            let ty = match &binding.ty {
+                Some(ty) => self.compile_typed(binding.value, &value, ty, scope)?,
+                None => self.compile_expression(binding.value, Some(&value), scope)?,
+            };
+            values.push(value);
+            types.push(ty);
+        }
+
+        let head = scope.symbol(Some("loop"));
+        self.emit(1, format!("br label %{}", head));
+        let entry = self.block.clone();
+        self.emit_label(&head);
+        // The phi nodes go here once every edge back is known
+        let phi_position = self.output.len();
+        let mut inner = scope.clone();
+        let params: Vec<String> = bindings
+            .iter()
+            .zip(&types)
+            .map(|(binding, ty)| inner.register_variable(binding.name.to_string(), ty.clone()))
+            .collect();
+
+        self.loops.push(Loop {
+            name: name.to_string(),
+            head,
+            bindings: types.clone(),
+            result: result.clone(),
+            incoming: vec![(values, entry)],
+        });
+        let (last, effects) = body.split_last().unwrap();
+        let compiled = self.compile_effects(effects, &mut inner).and_then(|_| {
+            self.tail = Some(Tail::Loop(name.to_string()));
+            self.compile_typed(last, destination.unwrap(), &result, &mut inner)
+        });
+        self.tail = None;
+        let incoming = self.loops.pop().unwrap().incoming;
+        compiled?;
+
+        let phis: String = params
+            .iter()
+            .zip(&types)
+            .enumerate()
+            .map(|(i, (param, ty))| {
+                let edges: Vec<String> = incoming
+                    .iter()
+                    .map(|(values, block)| format!("[ %{}, %{} ]", values[i], block))
+                    .collect();
+                format!(
+                    "\t%{} = phi {} {}\n",
+                    param,
+                    llvm_type(ty),
+                    edges.join(", ")
+                )
+            })
+            .collect();
+        self.output.insert_str(phi_position, &phis);
+        Ok(result)
+    }
+
+    // Call to the loop `self.loops[index]` in tail position of its body
+    fn compile_loop_jump(
+        &mut self,
+        index: usize,
+        args: &[Expression],
+        span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let bindings = self.loops[index].bindings.clone();
+        if args.len() != bindings.len() {
+            let expected = bindings.len().to_string();
+            return Err(arity_error(&self.loops[index].name, &expected, args.len(), span).into());
+        }
+        let mut values = Vec::with_capacity(args.len());
+        for (arg, ty) in args.iter().zip(bindings) {
+            let value = scope.symbol(None);
+            self.compile_typed(arg, &value, &ty, scope)?;
+            values.push(value);
+        }
+        let block = self.block.clone();
+        let Loop { head, result, .. } = &self.loops[index];
+        let (head, result) = (head.clone(), result.clone());
+        self.loops[index].incoming.push((values, block));
+        self.emit(1, format!("br label %{}", head));
+
+        // Whatever is done with the value after the jump is unreachable, but
+        // still needs a block and a value
+        let after = scope.symbol(Some("afterloop"));
+        self.emit_label(&after);
+        self.emit_copy(destination.unwrap(), &result, "undef");
+        Ok(result)
+    }
+
+    // Stores a new value in the slot of a variable, evaluating to it
+    fn compile_set() -> PrimitiveFunction {
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let (name, value) = split_set(expressions, span)?;
+            let (slot, ty) = match (scope.variable(name), scope.slot(name)) {
+                (Some((_, ty)), Some(slot)) if !scope.is_constant(name) => (slot.to_string(), ty),
+                (Some(_), Some(_)) => {
+                    return Err(cannot_assign(name, "a constant", expressions[0].span).into())
+                }
+                (Some(_), _) => {
+                    return Err(cannot_assign(name, UNASSIGNABLE, expressions[0].span).into())
+                }
+                (None, _) if backend.signatures.contains_key(name) => {
+                    return Err(cannot_assign(name, "a function", expressions[0].span).into())
+                }
+                (None, _) => {
+                    return Err(Diagnostic::error(
+                        "E0101",
+                        format!("attempt to assign undefined variable `{}`", name),
+                    )
+                    .with_span(expressions[0].span)
+                    .with_label("not found in this scope")
+                    .with_suggestion(name, scope.names())
+                    .into())
+                }
+            };
+            let destination = destination.unwrap();
+            backend.compile_typed(value, destination, &ty, scope)?;
+            let llvm_ty = llvm_type(&ty);
+            backend.emit(
+                1,
+                format!("store {} %{}, {}* {}", llvm_ty, destination, llvm_ty, slot),
+            );
+            Ok(ty)
+        };
+        Rc::new(c)
+    }
+
+    // Evaluates the body as long as the test is true, evaluating to 0
+    fn compile_while() -> PrimitiveFunction {
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let (test, body) = expressions
+                .split_first()
+                .ok_or_else(|| arity_error("while", "at least 1", 0, span))?;
+            let head = scope.symbol(Some("while"));
+            let body_label = scope.symbol(Some("whilebody"));
+            let end_label = scope.symbol(Some("whileend"));
+            backend.emit(1, format!("br label %{}", head));
+            backend.emit_label(&head);
+            let test_var = scope.symbol(None);
+            let ty = backend.compile_expression(test, Some(&test_var), scope)?;
+            expect_type(&Type::Bool, &ty, test.span)?;
+            backend.emit(
+                1,
+                format!(
+                    "br i1 %{}, label %{}, label %{}",
+                    test_var, body_label, end_label
+                ),
+            );
+
+            backend.emit_label(&body_label);
+            backend.compile_effects(body, scope)?;
+            backend.emit(1, format!("br label %{}", head));
+
+            backend.emit_label(&end_label);
+            backend.emit_copy(destination.unwrap(), &Type::DEFAULT, "0");
+            Ok(Type::DEFAULT)
+        };
+        Rc::new(c)
+    }
+
+    // Evaluates the body with a counter bound to each integer from 0 up to
+    // the count, excluded, evaluating to 0
+    fn compile_dotimes() -> PrimitiveFunction {
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let (counter, body) = split_dotimes(expressions, span)?;
+            let count = scope.symbol(None);
+            let ty = match counter.ty {
+                Some(ty) => backend.compile_typed(counter.value, &count, &ty, scope)?,
+                None => backend.compile_expression(counter.value, Some(&count), scope)?,
+            };
+            let int = match ty {
+                Type::Int(int) => int,
+                _ => return Err(expected_integer(&ty, counter.value.span).into()),
+            };
+            let llvm_ty = llvm_type(&ty);
+
+            let head = scope.symbol(Some("dotimes"));
+            let body_label = scope.symbol(Some("dotimesbody"));
+            let next_label = scope.symbol(Some("dotimesnext"));
+            let end_label = scope.symbol(Some("dotimesend"));
+            let mut inner = scope.clone();
+            let index = inner.register_variable(counter.name.to_string(), ty);
+            let next = inner.symbol(None);
+            let test = inner.symbol(None);
+            let entry = backend.block.clone();
+            backend.emit(1, format!("br label %{}", head));
+            backend.emit_label(&head);
+            backend.emit(
+                1,
+                format!(
+                    "%{} = phi {} [ 0, %{} ], [ %{}, %{} ]",
+                    index, llvm_ty, entry, next, next_label
+                ),
+            );
+            let less = if int.is_signed() { "slt" } else { "ult" };
+            backend.emit(
+                1,
+                format!(
+                    "%{} = icmp {} {} %{}, %{}",
+                    test, less, llvm_ty, index, count
+                ),
+            );
+            backend.emit(
+                1,
+                format!(
+                    "br i1 %{}, label %{}, label %{}",
+                    test, body_label, end_label
+                ),
+            );
+
+            backend.emit_label(&body_label);
+            backend.compile_effects(body, &mut inner)?;
+            backend.emit(1, format!("br label %{}", next_label));
+            backend.emit_label(&next_label);
+            backend.emit(1, format!("%{} = add {} %{}, 1", next, llvm_ty, index));
+            backend.emit(1, format!("br label %{}", head));
+
+            backend.emit_label(&end_label);
+            backend.emit_copy(destination.unwrap(), &Type::DEFAULT, "0");
+            Ok(Type::DEFAULT)
+        };
+        Rc::new(c)
+    }
+
+    // Writes a value to stdout followed by a newline, evaluating to 0
+    fn compile_print() -> PrimitiveFunction {
+        let c = move |backend: &mut LLVM,
+                      expressions: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            if expressions.len() != 1 {
+                return Err(arity_error("print", "1", expressions.len(), span).into());
+            }
+            let value = scope.symbol(None);
+            let ty = backend.compile_expression(&expressions[0], Some(&value), scope)?;
+
+            let status = scope.symbol(None);
+            match ty {
+                Type::Bool => {
+                    backend.declare("declare i32 @puts(i8*)");
+                    let strings = ["#t", "#f"]
+                        .iter()
+                        .map(|text| {
+                            let string = scope.symbol(None);
+                            let literal = ExpressionKind::String(text.to_string());
+                            backend.compile_expression(
+                                &Expression::new(literal, span),
+                                Some(&string),
+                                scope,
+                            )?;
+                            Ok(string)
+                        })
+                        .collect::<CompileResult<Vec<String>>>()?;
+                    let text = scope.symbol(None);
+                    backend.emit(
+                        1,
+                        format!(
+                            "%{} = select i1 %{}, i8* %{}, i8* %{}",
+                            text, value, strings[0], strings[1]
+                        ),
+                    );
+                    backend.emit(1, format!("%{} = call i32 @puts(i8* %{})", status, text));
+                }
+                Type::Str => {
+                    backend.declare("declare i32 @puts(i8*)");
+                    backend.emit(1, format!("%{} = call i32 @puts(i8* %{})", status, value));
+                }
+                Type::Function(_) => {
+                    backend.declare("declare i32 @puts(i8*)");
+                    let text = scope.symbol(None);
+                    backend.compile_expression(
+                        &Expression::new(ExpressionKind::String("#<function>".to_string()), span),
+                        Some(&text),
+                        scope,
+                    )?;
+                    backend.emit(1, format!("%{} = call i32 @puts(i8* %{})", status, text));
+                }
+                Type::Int(_) | Type::Float => {
+                    backend.declare("declare i32 @printf(i8*, ...)");
+                    // Integers are printed as 64 bit values
+                    let (value, ty) = match ty {
+                        Type::Int(int) if int.bits() < 64 => {
+                            let extended = scope.symbol(None);
+                            backend.emit(
+                                1,
+                                format!(
+                                    "%{} = sext {} %{} to i64",
+                                    extended,
+                                    llvm_type(&ty),
+                                    value
+                                ),
+                            );
+                            (extended, Type::DEFAULT)
+                        }
+                        _ => (value, ty),
+                    };
+                    let text = match ty {
+                        Type::Int(IntType::U64) => "%lu\n",
+                        Type::Int(_) => "%ld\n",
+                        _ => "%g\n",
+                    };
+                    let format = scope.symbol(None);
+                    backend.compile_expression(
+                        &Expression::new(ExpressionKind::String(text.to_string()), span),
+                        Some(&format),
+                        scope,
+                    )?;
+                    backend.emit(
+                        1,
+                        format!(
+                            "%{} = call i32 (i8*, ...) @printf(i8* %{}, {} %{})",
+                            status,
+                            format,
+                            llvm_type(&ty),
+                            value
+                        ),
+                    );
+                }
+            }
+
+            if let Some(destination) = destination {
+                backend.emit(1, format!("%{} = add i64 0, 0", destination));
+            }
+            Ok(Type::DEFAULT)
+        };
+        Rc::new(c)
+    }
+
+    fn write_asm(&mut self, output: &str, asm: String) -> CompileResult {
+        fs::File::create(output)
+            .and_then(|mut file| file.write_all(asm.as_bytes()))
+            .map_err(|error| {
+                Diagnostic::error("E0203", format!("failed to write `{}`", output))
+                    .with_note(error.to_string())
+                    .into()
+            })
+    }
+
+    fn run_assembler(&mut self, asmfile: &str, codefile: &str) -> CompileResult<String> {
+        let objfile = format!("{}.s", codefile);
+        // Position independent code, so gcc can link it into a PIE binary
+        run_tool(
+            Command::new("llc")
+                .arg("-relocation-model=pic")
+                .arg("-o")
+                .arg(&objfile)
+                .arg(asmfile),
+        )?;
+        Ok(objfile)
+    }
+
+    fn run_linker(&mut self, objfile: &str, binary: &str) -> CompileResult {
+        // frem, the remainder of floats, calls fmod from libm
+        run_tool(
+            Command::new("gcc")
+                .arg("-o")
+                .arg(binary)
+                .arg(objfile)
+                .arg("-lm"),
+        )
+    }
+}
+
+// Calling convention of a function defined by the program: the C one for
+// main, which the C runtime calls, a faster one for the others
+fn convention(function: &str) -> &'static str {
+    if function == "main" {
+        "ccc"
+    } else {
+        "fastcc"
+    }
+}
+
+// Type both operands of `operation` are converted to before applying it
+fn operand_type(
+    name: &str,
+    operation: &Operation,
+    ty1: &Type,
+    ty2: &Type,
+    span: Span,
+) -> CompileResult<Type> {
+    let ty = if operation.float.is_some() && (*ty1 == Type::Float || *ty2 == Type::Float) {
+        expect_number(ty1, span)?;
+        expect_number(ty2, span)?;
+        promoted_type(ty1, ty2, span)?
+    } else {
+        expect_type(ty1, ty2, span)?;
+        ty1.clone()
+    };
+    if operation.instruction(&ty).is_none() {
+        return Err(Diagnostic::error("E0109", "mismatched types")
+            .with_span(span)
+            .with_label(format!("`{}` cannot be applied to `{}`", name, ty))
+            .into());
+    }
+    Ok(ty)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OperationKind {
+    Arithmetic,
+    // Produces a bool rather than a value of the operand type
+    Comparison,
+    // Traps when an integer divisor is zero
+    Division,
+    // Remainder with the sign of the divisor, trapping like a division
+    Modulo,
+    // Shifts the first operand by the second one
+    Shift,
+}
+
+// How a binary operation is lowered for each type of operand; operands of a
+// type without an instruction are rejected
+#[derive(Clone, Copy)]
+struct Operation {
+    arity: Arity,
+    signed: Option<&'static str>,
+    unsigned: Option<&'static str>,
+    float: Option<&'static str>,
+    boolean: Option<&'static str>,
+    kind: OperationKind,
 }
 
-fn split_function(list: &Expression) -> (String, &[Expression]) {
-    if let Expression::List(vec) = list {
-        if let Expression::Symbol(name) = &vec[0] {
-            return (safe_name(name), vec.split_at(1).1);
+impl Operation {
+    const fn numeric(
+        kind: OperationKind,
+        arity: Arity,
+        signed: &'static str,
+        unsigned: &'static str,
+        float: &'static str,
+    ) -> Self {
+        Operation {
+            arity,
+            signed: Some(signed),
+            unsigned: Some(unsigned),
+            float: Some(float),
+            boolean: None,
+            kind,
+        }
+    }
+
+    const fn integer(
+        kind: OperationKind,
+        arity: Arity,
+        signed: &'static str,
+        unsigned: &'static str,
+        boolean: Option<&'static str>,
+    ) -> Self {
+        Operation {
+            arity,
+            signed: Some(signed),
+            unsigned: Some(unsigned),
+            float: None,
+            boolean,
+            kind,
+        }
+    }
+
+    const fn with_boolean(mut self, boolean: &'static str) -> Self {
+        self.boolean = Some(boolean);
+        self
+    }
+
+    fn instruction(&self, ty: &Type) -> Option<&'static str> {
+        match ty {
+            Type::Int(IntType::U64) => self.unsigned,
+            Type::Int(_) => self.signed,
+            Type::Float => self.float,
+            Type::Bool => self.boolean,
+            Type::Str | Type::Function(_) => None,
+        }
+    }
+}
+
+const OPERATIONS: &[(&str, Operation)] = {
+    use Arity::*;
+    use Constant::*;
+    use OperationKind::*;
+    &[
+        (
+            "+",
+            Operation::numeric(Arithmetic, Fold(Integer(0)), "add", "add", "fadd"),
+        ),
+        (
+            "-",
+            Operation::numeric(Arithmetic, Inverse(Integer(0)), "sub", "sub", "fsub"),
+        ),
+        (
+            "*",
+            Operation::numeric(Arithmetic, Fold(Integer(1)), "mul", "mul", "fmul"),
+        ),
+        (
+            "/",
+            Operation::numeric(Division, Inverse(Integer(1)), "sdiv", "udiv", "fdiv"),
+        ),
+        (
+            "rem",
+            Operation::numeric(Division, Binary, "srem", "urem", "frem"),
+        ),
+        (
+            "mod",
+            Operation::numeric(Modulo, Binary, "srem", "urem", "frem"),
+        ),
+        (
+            "=",
+            Operation::numeric(Comparison, Chain, "icmp eq", "icmp eq", "fcmp oeq")
+                .with_boolean("icmp eq"),
+        ),
+        (
+            "!=",
+            Operation::numeric(Comparison, Binary, "icmp ne", "icmp ne", "fcmp une")
+                .with_boolean("icmp ne"),
+        ),
+        (
+            "<",
+            Operation::numeric(Comparison, Chain, "icmp slt", "icmp ult", "fcmp olt"),
+        ),
+        (
+            "<=",
+            Operation::numeric(Comparison, Chain, "icmp sle", "icmp ule", "fcmp ole"),
+        ),
+        (
+            ">",
+            Operation::numeric(Comparison, Chain, "icmp sgt", "icmp ugt", "fcmp ogt"),
+        ),
+        (
+            ">=",
+            Operation::numeric(Comparison, Chain, "icmp sge", "icmp uge", "fcmp oge"),
+        ),
+        (
+            "and",
+            Operation::integer(Arithmetic, Fold(Boolean(true)), "and", "and", Some("and")),
+        ),
+        (
+            "or",
+            Operation::integer(Arithmetic, Fold(Boolean(false)), "or", "or", Some("or")),
+        ),
+        (
+            "xor",
+            Operation::integer(Arithmetic, Fold(Boolean(false)), "xor", "xor", Some("xor")),
+        ),
+        ("shl", Operation::integer(Shift, Binary, "shl", "shl", None)),
+        (
+            "shr",
+            Operation::integer(Shift, Binary, "ashr", "lshr", None),
+        ),
+    ]
+};
+
+fn llvm_type(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int(IntType::I8) => "i8",
+        Type::Int(IntType::I16) => "i16",
+        Type::Int(IntType::I32) => "i32",
+        Type::Int(IntType::I64) | Type::Int(IntType::U64) => "i64",
+        Type::Float => "double",
+        Type::Bool => "i1",
+        Type::Str | Type::Function(_) => "i8*",
+    }
+}
+
+// Struct holding a closure: the code of its function followed by the values
+// it captured
+fn closure_type(captures: &[String]) -> String {
+    let mut fields = vec!["i8*"];
+    fields.extend(captures.iter().map(String::as_str));
+    format!("{{ {} }}", fields.join(", "))
+}
+
+// Variable captured by a closure
+struct Capture {
+    name: String,
+    // Its value, or the pointer to its cell
+    value: String,
+    ty: Type,
+    cell: bool,
+}
+
+impl Capture {
+    // LLVM type of the field of the closure holding it
+    fn llvm_type(&self) -> String {
+        if self.cell {
+            format!("{}*", llvm_type(&self.ty))
         } else {
-            panic!("First list item is not a symbol");
+            llvm_type(&self.ty).to_string()
+        }
+    }
+}
+
+// Pointer to the code of a closure with `signature`, which takes the closure
+// itself before its params
+fn closure_function_type(signature: &Signature) -> String {
+    let mut params = vec!["i8*"];
+    params.extend(signature.params.iter().map(|ty| llvm_type(ty)));
+    format!("{} ({})*", llvm_type(&signature.result), params.join(", "))
+}
+
+// Every symbol in an expression
+fn symbols(expression: &Expression) -> Vec<&str> {
+    match &expression.kind {
+        ExpressionKind::Symbol(symbol) => vec![symbol],
+        ExpressionKind::List(items) => items.iter().flat_map(symbols).collect(),
+        _ => vec![],
+    }
+}
+
+fn split_function(list: &Expression) -> CompileResult<(&str, &[Expression])> {
+    if let ExpressionKind::List(vec) = &list.kind {
+        match vec.first() {
+            Some(Expression {
+                kind: ExpressionKind::Symbol(name),
+                ..
+            }) => Ok((name, vec.split_at(1).1)),
+            Some(head) => Err(
+                Diagnostic::error("E0105", "first list item is not a symbol")
+                    .with_span(head.span)
+                    .with_label("expected a function name")
+                    .into(),
+            ),
+            None => Err(Diagnostic::error("E0105", "cannot evaluate an empty list")
+                .with_span(list.span)
+                .with_label("expected a function call")
+                .into()),
         }
     } else {
-        panic!("Expression is not a list item");
+        Err(Diagnostic::error("E0105", "expression is not a list item")
+            .with_span(list.span)
+            .into())
     }
 }
 
-fn split_def_expression(args: &[Expression]) -> (String, &Vec<Expression>, &Expression) {
-    (
-        if let Expression::Symbol(name) = &args[0] {
-            safe_name(name)
-        } else {
-            panic!("First item must be a symbol in def statement");
-        },
-        if let Expression::List(vec) = &args[1] {
-            vec
-        } else {
-            panic!("Second item must be a list in def statement");
-        },
-        if let Expression::List(_) = &args[2] {
-            &args[2]
+// Splits a def form into its name, return type, typed params and the
+// expressions of its body
+fn split_def_expression(args: &[Expression], span: Span) -> CompileResult<Definition<'_>> {
+    if args.len() < 3 {
+        return Err(arity_error("def", "at least 3", args.len(), span).into());
+    }
+    let (name, result) = if let ExpressionKind::Symbol(name) = &args[0].kind {
+        split_annotation(name, args[0].span)?
+    } else {
+        return Err(malformed_def("first item must be a symbol", args[0].span).into());
+    };
+    let params = split_params(&args[1])
+        .ok_or_else(|| malformed_def("second item must be a list", args[1].span))??;
+    Ok((name.to_string(), result, params, &args[2..]))
+}
+
+// Names and types of the params in a list of them, None if it isn't a list
+fn split_params(list: &Expression) -> Option<CompileResult<Vec<Param>>> {
+    let params = match &list.kind {
+        ExpressionKind::List(params) => params,
+        _ => return None,
+    };
+    let params = params.iter().map(|param| {
+        if let ExpressionKind::Symbol(param_name) = &param.kind {
+            let (param_name, ty) = split_annotation(param_name, param.span)?;
+            Ok((param_name.to_string(), ty))
         } else {
-            panic!("Third item must be a list in def statement");
-        },
-    )
+            Err(
+                Diagnostic::error("E0104", "function param must be a symbol")
+                    .with_span(param.span)
+                    .with_label("expected a symbol")
+                    .into(),
+            )
+        }
+    });
+    Some(params.collect())
+}
+
+// Type of the value of an expression when it shows without compiling it:
+// that of a literal or a comparison, or of the value some branch or body of
+// it ends with. A lambda gives that of its body.
+fn result_hint(expression: &Expression) -> Option<Type> {
+    let items = match &expression.kind {
+        ExpressionKind::List(items) => items,
+        ExpressionKind::Integer(_, ty) => return Some(ty.map_or(Type::DEFAULT, Type::Int)),
+        ExpressionKind::Float(_) => return Some(Type::Float),
+        ExpressionKind::Boolean(_) => return Some(Type::Bool),
+        ExpressionKind::String(_) => return Some(Type::Str),
+        ExpressionKind::Symbol(_) => return None,
+    };
+    let (head, args) = items.split_first()?;
+    let name = match &head.kind {
+        ExpressionKind::Symbol(name) => name.as_str(),
+        _ => return None,
+    };
+    let comparison = OPERATIONS
+        .iter()
+        .any(|(op, operation)| *op == name && operation.kind == OperationKind::Comparison);
+    if comparison {
+        return Some(Type::Bool);
+    }
+    if CONDITIONALS.contains(&name) {
+        let conditional = split_conditional(name, args, expression.span).ok()?;
+        return [conditional.then.last(), conditional.otherwise.last()]
+            .iter()
+            .flatten()
+            .find_map(|body| result_hint(body));
+    }
+    match name {
+        "begin" | "progn" | "let" | "let*" | "letrec" | "lambda" | "fn" => {
+            result_hint(args.last()?)
+        }
+        _ => None,
+    }
+}
+
+fn malformed_def(message: &str, span: Span) -> Diagnostic {
+    Diagnostic::error("E0103", format!("{} in def statement", message)).with_span(span)
 }
 
-pub(crate) fn new() -> Box<Backend<S = Scope>> {
+pub(crate) fn new() -> Box<dyn Backend<S = Scope>> {
     Box::new(LLVM::new())
 }
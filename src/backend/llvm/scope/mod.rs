@@ -1,52 +1,172 @@
 #[cfg(test)]
 mod tests;
 
-use std::collections::HashMap;
+use crate::backend::Type;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 #[derive(Clone, Debug)]
 pub struct Scope {
     locals: HashMap<String, String>,
+    // Names generated by `symbol`, hidden from `names`
+    temporaries: HashSet<String>,
+    // Types of the locals holding values, i.e. not functions
+    types: HashMap<String, Type>,
+    // Locals kept in memory, with the pointer to it: a stack slot, so they
+    // can be assigned, or a global
+    slots: HashMap<String, String>,
+    // Globals that can't be assigned
+    constants: HashSet<String>,
+    // Locals kept in a cell on the heap, which closures capture rather than
+    // its value, so they see the value bound after they're made
+    cells: HashSet<String>,
+    // Safe names given out in the function, shared with the scopes nested in
+    // it so a name shadowing another never gets the same safe name
+    taken: Rc<RefCell<HashSet<String>>>,
 }
 
 impl Scope {
     pub fn new() -> Self {
         Scope {
             locals: HashMap::new(),
+            temporaries: HashSet::new(),
+            types: HashMap::new(),
+            slots: HashMap::new(),
+            constants: HashSet::new(),
+            cells: HashSet::new(),
+            taken: Rc::new(RefCell::new(HashSet::new())),
         }
     }
 
     pub fn register(&mut self, local: String) -> String {
         let mut copy = safe_name(&local);
         let mut n = 1;
-        while self.locals.get(&copy).is_some() {
-            copy = format!("{}{}", local, n);
+        while self.locals.contains_key(&copy) || self.taken.borrow().contains(&copy) {
+            copy = format!("{}{}", safe_name(&local), n);
             n += 1;
         }
+        self.taken.borrow_mut().insert(copy.clone());
         self.locals.insert(local, copy.to_owned());
         copy
     }
 
+    pub(crate) fn register_variable(&mut self, local: String, ty: Type) -> String {
+        self.slots.remove(&local);
+        self.constants.remove(&local);
+        self.cells.remove(&local);
+        self.types.insert(local.clone(), ty);
+        self.register(local)
+    }
+
+    // Binds `local` to the value of an existing safe name
+    pub(crate) fn bind_variable(&mut self, local: String, safe: String, ty: Type) {
+        self.slots.remove(&local);
+        self.constants.remove(&local);
+        self.cells.remove(&local);
+        self.types.insert(local.clone(), ty);
+        self.locals.insert(local, safe);
+    }
+
+    // Registers a variable kept in a stack slot, returning the safe name of
+    // the slot
+    pub(crate) fn register_slot(&mut self, local: String, ty: Type) -> String {
+        let slot = self.register_variable(local.clone(), ty);
+        self.slots.insert(local, format!("%{}", slot));
+        slot
+    }
+
+    // Registers a variable kept in a cell, returning the safe name of the
+    // pointer to it
+    pub(crate) fn register_cell(&mut self, local: String, ty: Type) -> String {
+        let cell = self.register_slot(local.clone(), ty);
+        self.cells.insert(local);
+        cell
+    }
+
+    // Binds `local` to the cell pointed to by an existing safe name
+    pub(crate) fn bind_cell(&mut self, local: String, cell: String, ty: Type) {
+        self.bind_variable(local.clone(), cell.clone(), ty);
+        self.slots.insert(local.clone(), format!("%{}", cell));
+        self.cells.insert(local);
+    }
+
+    // Safe name of the pointer to the cell of a variable, if it's kept in one
+    pub(crate) fn cell(&self, local: &str) -> Option<&str> {
+        if !self.cells.contains(local) {
+            return None;
+        }
+        self.locals.get(local).map(String::as_str)
+    }
+
+    // Registers a global, returning its name in the module
+    pub(crate) fn register_global(&mut self, local: String, ty: Type, constant: bool) -> String {
+        let global = format!("{}.global", self.register_variable(local.clone(), ty));
+        self.slots.insert(local.clone(), format!("@{}", global));
+        if constant {
+            self.constants.insert(local);
+        }
+        global
+    }
+
+    // Pointer to the memory holding a variable, if it's kept there
+    pub(crate) fn slot(&self, local: &str) -> Option<&str> {
+        self.slots.get(local).map(String::as_str)
+    }
+
+    pub(crate) fn is_constant(&self, local: &str) -> bool {
+        self.constants.contains(local)
+    }
+
     pub fn symbol(&mut self, prefix: Option<&str>) -> String {
-        let nth = self.locals.len() + 1;
-        let prefix = prefix.unwrap_or_else(|| "sym");
-        self.register(format!("{}{}", prefix, nth))
+        let nth = self.taken.borrow().len() + 1;
+        let prefix = prefix.unwrap_or("sym");
+        let local = format!("{}{}", prefix, nth);
+        self.temporaries.insert(local.clone());
+        self.register(local)
     }
 
     pub fn get(&mut self, local: &str) -> Option<String> {
-        match self.locals.get(local) {
-            Some(s) => Some(s.clone()),
-            None => None,
-        }
+        self.locals.get(local).cloned()
+    }
+
+    // Safe name and type of a variable
+    pub(crate) fn variable(&self, local: &str) -> Option<(String, Type)> {
+        let ty = self.types.get(local)?;
+        Some((self.locals.get(local)?.clone(), ty.clone()))
     }
 
+    // Names registered by the program itself, i.e. functions and parameters
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.locals
+            .keys()
+            .filter(move |name| !self.temporaries.contains(*name))
+            .map(String::as_str)
+    }
+
+    // Scope for the body of a function, seeing the names of this one but
+    // giving out safe names of its own. A plain clone is a nested scope of
+    // the same function.
     pub fn copy(&mut self) -> Scope {
-        self.clone()
+        let mut copy = self.clone();
+        copy.taken = Rc::new(RefCell::new(self.locals.values().cloned().collect()));
+        copy
     }
 }
 
+// LLVM identifier for a symbol: dashes become underscores, and any other
+// character LLVM doesn't allow unquoted its code in hex, e.g. `even?` is
+// `even_3f`
 pub(crate) fn safe_name(symbol_name: &str) -> String {
     if symbol_name == "-" {
         return symbol_name.to_owned();
     }
-    symbol_name.replace("-", "_")
+    symbol_name
+        .chars()
+        .map(|c| match c {
+            '-' => "_".to_string(),
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => c.to_string(),
+            c => format!("_{:x}", c as u32),
+        })
+        .collect()
 }
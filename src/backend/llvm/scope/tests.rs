@@ -1 +1,39 @@
+use super::*;
 
+#[test]
+fn nested_scopes_give_shadowing_names_new_safe_names() {
+    let mut function = Scope::new().copy();
+    let outer = function.register_variable("x".to_string(), Type::DEFAULT);
+
+    let mut first = function.clone();
+    let inner = first.register_variable("x".to_string(), Type::Bool);
+    let mut second = function.clone();
+    let sibling = second.register_variable("x".to_string(), Type::Float);
+    assert_eq!(
+        (outer.as_str(), inner.as_str(), sibling.as_str()),
+        ("x", "x1", "x2")
+    );
+
+    assert_eq!(first.variable("x"), Some(("x1".to_string(), Type::Bool)));
+    assert_eq!(
+        function.variable("x"),
+        Some(("x".to_string(), Type::DEFAULT))
+    );
+
+    // Another function starts over
+    let mut other = Scope::new().copy();
+    assert_eq!(other.register_variable("x".to_string(), Type::DEFAULT), "x");
+}
+
+#[test]
+fn safe_names_are_valid_llvm_identifiers() {
+    let mut scope = Scope::new();
+    let names: Vec<String> = ["plus-two", "even?", "set-x!", "a*b", "λ"]
+        .iter()
+        .map(|name| scope.register(name.to_string()))
+        .collect();
+    assert_eq!(
+        names,
+        vec!["plus_two", "even_3f", "set_x_21", "a_2ab", "_3bb"]
+    );
+}
@@ -0,0 +1,343 @@
+use super::*;
+use crate::parser::parse;
+
+fn compile(code: &str) -> String {
+    LLVM::new().compile(&parse(code).unwrap()).unwrap()
+}
+
+// Builds the program and runs it, returning what it prints
+fn run(code: &str, name: &str) -> String {
+    let mut backend = LLVM::new();
+    let ir = backend.compile(&parse(code).unwrap()).unwrap();
+    let input = std::env::temp_dir().join(format!("ulisp-{}-{}", name, std::process::id()));
+    let input = input.to_str().unwrap();
+    let binary = format!("{}.out", input);
+    backend.build(ir, input, &binary).unwrap();
+    let output = Command::new(&binary).output().unwrap();
+    for extension in &["ll", "s", "out"] {
+        let _ = fs::remove_file(format!("{}.{}", input, extension));
+    }
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn calls_in_tail_position_are_tail_calls() {
+    let ir = compile(include_str!("../../../examples/countdown.ulisp"));
+    // Guaranteed when the params and calling convention match, only allowed
+    // otherwise
+    assert!(ir.contains(" = musttail call fastcc i64 @count_down("));
+    assert!(ir.contains(" = tail call fastcc i64 @count_down("));
+    // The block ends with the return, nothing is emitted after it
+    let lines: Vec<&str> = ir.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        if line.contains("tail call ") {
+            assert!(lines[i + 1].starts_with("\tret "), "{}", lines[i + 1]);
+        }
+    }
+    // So the branches left jump to the end of the if from existing blocks
+    let phi = lines.iter().find(|line| line.contains(" = phi ")).unwrap();
+    for incoming in phi.split('[').skip(1) {
+        let label = incoming.split(", %").nth(1).unwrap().trim_end_matches(']');
+        assert!(lines.contains(&format!("{}:", label).as_str()), "{}", phi);
+    }
+
+    let ir = compile(include_str!("../../../examples/fibonacci.ulisp"));
+    assert!(ir.contains(" = call fastcc i64 @fib("));
+    assert!(!ir.contains("musttail"));
+    // The C runtime calls main
+    assert!(ir.contains("define ccc i64 @main() {"));
+}
+
+#[test]
+fn tail_calls_run_in_constant_stack_space() {
+    let code = "(module
+                  (def count-down (n) (if (= n 0) 0 (count-down (- n 1))))
+                  (def main () (print (count-down 1000000)) 0))";
+    let ir = compile(code);
+    let start = ir.find("define fastcc i64 @count_down(").unwrap();
+    let function = &ir[start..start + ir[start..].find("\n}\n").unwrap()];
+    assert!(function.contains(" = musttail call fastcc i64 @count_down("));
+    assert!(!function.contains(" = call "));
+    assert_eq!(run(code, "countdown"), "0\n");
+}
+
+#[test]
+fn integer_division_traps_on_a_zero_divisor() {
+    let ir = compile("(def f (x) (/ x 0))");
+    assert!(ir.contains("declare void @llvm.trap() noreturn nounwind\n"));
+    let check = ir.find(" = icmp eq i64 ").unwrap();
+    let trap = ir
+        .find("\tcall void @llvm.trap()\n\tunreachable\n")
+        .unwrap();
+    let division = ir.find(" = sdiv i64 ").unwrap();
+    assert!(check < trap && trap < division);
+
+    // Floats divide by zero to an infinity instead
+    let ir = compile("(def f:f64 (x:f64) (/ x 0.0))");
+    assert!(!ir.contains("llvm.trap"));
+}
+
+#[test]
+fn mod_takes_the_sign_of_the_divisor_and_rem_of_the_dividend() {
+    // (mod -7 2) is 1, the remainder moved into the range of the divisor
+    let ir = compile("(def f () (mod -7 2))");
+    assert!(ir.contains(" = srem i64 "));
+    assert!(ir.contains(" = icmp slt i64 "));
+    assert!(ir.contains(" = select i1 "));
+
+    // (rem -7 2) is -1, the remainder as it comes
+    let ir = compile("(def f () (rem -7 2))");
+    assert!(ir.contains(" = srem i64 "));
+    assert!(!ir.contains(" = select "));
+}
+
+#[test]
+fn shift_amounts_are_masked_to_the_width_of_the_operand() {
+    let ir = compile("(def f:i32 (a:i32 b:i32) (shl a b))");
+    let mask = ir
+        .lines()
+        .find(|line| line.contains(" = and i32 "))
+        .unwrap();
+    assert!(mask.ends_with(", 31"));
+    assert!(ir.contains(" = shl i32 "));
+}
+
+#[test]
+fn comparisons_are_widened_where_an_integer_is_expected() {
+    let ir = compile("(def f (a b) (< a b))");
+    assert!(ir.contains(" = icmp slt i64 "));
+    assert!(ir.contains(" = zext i1 %"));
+    assert!(ir.contains(" to i64\n\tret i64 "));
+}
+
+#[test]
+fn variadic_operations_fold_their_operands() {
+    // No operands is the identity
+    let ir = compile("(def f () (+))");
+    assert!(ir.contains(" = add i64 0, 0\n"));
+
+    // A single operand of `-` is negated
+    let ir = compile("(def f (x) (- x))");
+    assert!(ir.contains(" = sub i64 0, %"));
+
+    // Chained comparisons compare each adjacent pair
+    let ir = compile("(def f:bool (a b c) (< a b c))");
+    assert_eq!(ir.matches(" = icmp slt i64 ").count(), 2);
+    assert!(ir.contains(" = and i1 "));
+}
+
+#[test]
+fn operations_without_operands_need_an_identity() {
+    let errors = LLVM::new()
+        .compile(&parse("(def f () (/))").unwrap())
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "E0106");
+    assert_eq!(errors[0].message, "`/` takes at least 1 argument, found 0");
+}
+
+#[test]
+fn named_let_bindings_are_phi_nodes_of_every_edge_into_the_loop() {
+    let ir = compile(
+        "(def sum (n)
+           (let loop ((i 0) (total 0))
+             (if (> i n) total (loop (+ i 1) (+ total i)))))",
+    );
+    let phi = ir.lines().find(|line| line.starts_with("\t%i = phi i64 "));
+    // From before the loop and from the call starting it over
+    assert_eq!(phi.map(|line| line.matches('[').count()), Some(2));
+    assert!(!ir.contains("@loop"));
+}
+
+#[test]
+fn assigned_variables_get_a_stack_slot_in_the_entry_block() {
+    let ir = compile(
+        "(def f (n)
+           (while (> n 0)
+             (let ((m n) (unused 1))
+               (set! m (- m 1))
+               (set! n m)))
+           n)",
+    );
+    let allocas: Vec<usize> = ir
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.contains(" = alloca "))
+        .map(|(i, _)| i)
+        .collect();
+    // The param and `m`, before the loop starts
+    assert_eq!(allocas.len(), 2);
+    let first_branch = ir.lines().position(|line| line.contains("br ")).unwrap();
+    assert!(allocas.iter().all(|i| *i < first_branch));
+}
+
+#[test]
+fn globals_are_initialized_with_values_computed_at_compile_time() {
+    let ir = compile(
+        "(module
+           (def main () (set! total (+ total half)) 0)
+           (defconst size:i32 10)
+           (defconst half:i32 (/ size 2))
+           (defvar total:i32 (* half 3)))",
+    );
+    assert!(ir.contains("@size.global = constant i32 10\n"));
+    assert!(ir.contains("@half.global = constant i32 5\n"));
+    assert!(ir.contains("@total.global = global i32 15\n"));
+    assert!(ir.contains("store i32 %"));
+}
+
+#[test]
+fn and_of_bools_only_evaluates_operands_until_one_is_false() {
+    let ir = compile(
+        "(module
+           (def g:bool () true)
+           (def f:bool (a:bool b:bool) (and a (= b true) (g))))",
+    );
+    // Each operand but the last branches to the end, whose value comes from
+    // whichever block got there
+    assert_eq!(ir.matches("label %andend").count(), 3);
+    assert!(ir.contains(" = phi i1 [false, %entry"));
+    assert!(!ir.contains(" = and i1 "));
+
+    let ir = compile("(def f (a b) (and a b))");
+    assert!(ir.contains(" = and i64 "));
+}
+
+#[test]
+fn if_values_are_phi_nodes_of_the_blocks_each_branch_ends_in() {
+    let ir = compile("(def f (a b) (if (> a 0) (if (> b 0) 1 2) 3))");
+    let lines: Vec<&str> = ir.lines().collect();
+    // Each phi with the label of the block it starts
+    let phis: Vec<(&str, &str)> = lines
+        .windows(2)
+        .filter(|pair| pair[1].contains(" = phi "))
+        .map(|pair| (pair[0].trim_end_matches(':'), pair[1]))
+        .collect();
+    // The outer if is entered from the end of the inner one rather than
+    // from the block its true branch starts in
+    let [(inner_end, _), (_, outer)] = phis[..] else {
+        panic!("expected 2 phi nodes, found {}", phis.len());
+    };
+    assert!(outer.contains(&format!(", %{}]", inner_end)));
+    assert!(!ir.contains("alloca"));
+}
+
+#[test]
+fn letrec_lambdas_can_call_themselves_and_later_bindings() {
+    let output = run(
+        "(def main ()
+           (letrec ((fact (lambda (n) (if (= n 0) 1 (* n (fact (- n 1))))))
+                    (is-even (lambda (n) (if (= n 0) 1 (is-odd (- n 1)))))
+                    (is-odd (lambda (n) (if (= n 0) 0 (is-even (- n 1))))))
+             (print (fact 5))
+             (print (is-even 10))
+             (print (is-odd 10))
+             0))",
+        "letrec",
+    );
+    assert_eq!(output, "120\n1\n0\n");
+
+    // Only lambdas wait for the later bindings to have a value
+    let errors = LLVM::new()
+        .compile(&parse("(def f () (letrec ((a (+ b 1)) (b 1)) a))").unwrap())
+        .unwrap_err();
+    assert_eq!(errors[0].code, "E0113");
+    assert_eq!(errors[0].message, "`b` is used before it's initialized");
+}
+
+#[test]
+fn letrec_lambdas_called_before_they_are_bound_return_the_type_of_their_body() {
+    let output = run(
+        "(def main ()
+           (letrec ((is-even (lambda (n) (if (= n 0) #t (is-odd (- n 1)))))
+                    (is-odd (lambda (n) (cond ((= n 0) #f) (else (is-even (- n 1))))))
+                    (half (lambda (n) (if (is-even n) 0.5 (half (+ n 1))))))
+             (print (is-even 10))
+             (print (is-odd 7))
+             (print (half 5))
+             0))",
+        "letrec-bools",
+    );
+    assert_eq!(output, "#t\n#t\n0.5\n");
+}
+
+#[test]
+fn names_with_punctuation_compile() {
+    let output = run(
+        "(defvar count! 0)
+         (def set-count! (n) (set! count! n))
+         (def a*b (a b) (* a b))
+         (def main ()
+           (let ((even? (lambda (n) (= (rem n 2) 0))))
+             (set-count! (a*b 3 4))
+             (print (even? count!))
+             (print (even? 7))
+             0))",
+        "punctuation",
+    );
+    assert_eq!(output, "#t\n#f\n");
+}
+
+#[test]
+fn lambdas_share_globals_with_the_rest_of_the_program() {
+    let output = run(
+        "(defvar g 1)
+         (def main ()
+           (let ((get (lambda () g))
+                 (bump (lambda (n) (set! g (+ g n)))))
+             (set! g 100)
+             (print (get))
+             (bump 5)
+             (print g)
+             (print (get))
+             0))",
+        "lambda-globals",
+    );
+    assert_eq!(output, "100\n105\n105\n");
+}
+
+#[test]
+fn bodies_evaluate_every_expression_and_return_the_last() {
+    let output = run(
+        "(module
+           (def f (x)
+             (set! x (+ x 1))
+             (begin (print x) (set! x (* x 2)) x))
+           (def main ()
+             (print (f 4))
+             (print (let ((y 1)) (set! y 7) (progn 3 y)))
+             0))",
+        "bodies",
+    );
+    assert_eq!(output, "5\n10\n7\n");
+}
+
+#[test]
+fn closures_capture_free_variables_and_outlive_their_scope() {
+    let output = run(
+        "(module
+           (def adder:i64->i64 (n) (lambda (x) (+ x n)))
+           (def twice (f:i64->i64 x) (f (f x)))
+           (def square (x) (* x x))
+           (def main ()
+             (let ((k 10))
+               (let ((scale (lambda (x) (* x k))))
+                 (print (scale 4))))
+             (let ((add5 (adder 5)))
+               (print (add5 1)))
+             (print (twice square 3))
+             0))",
+        "closures",
+    );
+    assert_eq!(output, "40\n6\n81\n");
+
+    // A def used as a value calls it through a closure capturing nothing
+    let ir = compile(
+        "(module
+           (def square (x) (* x x))
+           (def twice (f:i64->i64 x) (f (f x)))
+           (def main () (twice square 3)))",
+    );
+    assert!(ir.contains("@square.value = constant { i8* }"));
+    assert!(ir.contains("define i64 @square.closure(i8* %env, i64 %p0)"));
+}
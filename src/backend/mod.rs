@@ -1,8 +1,12 @@
 pub mod llvm;
 pub mod x86;
 
-use crate::parser::Expression;
+use crate::diagnostics::Diagnostic;
+use crate::parser::{Expression, ExpressionKind, IntType, Span};
+use std::collections::HashMap;
 use std::fmt;
+use std::process::Command;
+use std::rc::Rc;
 use std::str::FromStr;
 
 #[derive(Debug)]
@@ -28,6 +32,7 @@ impl std::error::Error for BackendOptError {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum BackendOpt {
     LLVM,
     X86,
@@ -47,44 +52,929 @@ impl FromStr for BackendOpt {
     }
 }
 
+// Diagnostics are boxed so results stay as small as the values they hold
+pub(crate) type CompileResult<T = ()> = Result<T, Box<Diagnostic>>;
+
+// Static type of a compiled value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Type {
+    Int(IntType),
+    Float,
+    Bool,
+    Str,
+    // Function value, written `params->result` with comma separated params
+    Function(Rc<Signature>),
+}
+
+impl Type {
+    // Type of integer literals without a suffix, and of unannotated names
+    pub(crate) const DEFAULT: Type = Type::Int(IntType::I64);
+
+    // Type of functions with `signature`, shared so types stay cheap to clone
+    pub(crate) fn function(signature: Signature) -> Type {
+        Type::Function(Rc::new(signature))
+    }
+
+    fn from_name(name: &str) -> Option<Type> {
+        if let Some((params, result)) = name.split_once("->") {
+            let params = params
+                .split(',')
+                .filter(|param| !param.is_empty())
+                .map(Type::from_name)
+                .collect::<Option<Vec<Type>>>()?;
+            let result = Type::from_name(result)?;
+            return Some(Type::function(Signature { params, result }));
+        }
+        match name {
+            "f64" => Some(Type::Float),
+            "bool" => Some(Type::Bool),
+            "str" => Some(Type::Str),
+            _ => IntType::ALL
+                .iter()
+                .find(|int| int.name() == name)
+                .map(|int| Type::Int(*int)),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int(int) => write!(f, "{}", int),
+            Type::Float => write!(f, "f64"),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "str"),
+            Type::Function(signature) => {
+                // Function params are parenthesized, their arrow would be
+                // ambiguous
+                let params: Vec<String> = signature
+                    .params
+                    .iter()
+                    .map(|param| match param {
+                        Type::Function(_) => format!("({})", param),
+                        _ => param.to_string(),
+                    })
+                    .collect();
+                write!(f, "{}->{}", params.join(","), signature.result)
+            }
+        }
+    }
+}
+
+// Parameter and return types of a function
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Signature {
+    pub params: Vec<Type>,
+    pub result: Type,
+}
+
+// Name of a function parameter and its type
+pub(crate) type Param = (String, Type);
+
+// Name, return type, params and body expressions of a def form
+pub(crate) type Definition<'a> = (String, Type, Vec<Param>, &'a [Expression]);
+
 pub(crate) trait Backend {
     type S;
 
-    fn compile(&mut self, ast: &Expression) -> String;
+    // Generates code for the whole program, returning every error found
+    fn compile(&mut self, ast: &Expression) -> Result<String, Vec<Diagnostic>>;
 
-    fn build(&mut self, asm: String, input: &str, output: &str);
+    fn build(&mut self, asm: String, input: &str, output: &str) -> CompileResult;
 
     fn compile_expression(
         &mut self,
         arg: &Expression,
         destination: Option<&str>,
         scope: &mut Self::S,
-    );
+    ) -> CompileResult<Type>;
 
     fn compile_call(
         &mut self,
         function: &str,
         args: &[Expression],
+        span: Span,
         destination: Option<&str>,
         scope: &mut Self::S,
-    );
+    ) -> CompileResult<Type>;
 
     fn compile_define(
         &mut self,
         args: &[Expression],
+        span: Span,
         _destination: Option<&str>,
         scope: &mut Self::S,
-    );
+    ) -> CompileResult<Type>;
 
     fn compile_module(
         &mut self,
         args: &[Expression],
+        span: Span,
         destination: Option<&str>,
         scope: &mut Self::S,
-    );
+    ) -> CompileResult<Type>;
 
     fn emit<T>(&mut self, depth: usize, code: T)
     where
         T: Into<String>,
         Self: Sized;
 }
+
+// Runs an external tool of the build pipeline, reporting a diagnostic when it
+// can't be started or exits with an error
+pub(crate) fn run_tool(command: &mut Command) -> CompileResult {
+    let program = format!("{:?}", command.get_program());
+    let program = program.trim_matches('"');
+    let output = command.output().map_err(|error| {
+        Diagnostic::error("E0201", format!("failed to run `{}`", program))
+            .with_note(error.to_string())
+    })?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Diagnostic::error(
+            "E0202",
+            format!("`{}` exited with {}", program, output.status),
+        )
+        .with_note(String::from_utf8_lossy(&output.stderr).trim().to_string())
+        .into())
+    }
+}
+
+// Error unless a value of type `found` can be used where `expected` is needed
+pub(crate) fn expect_type(expected: &Type, found: &Type, span: Span) -> CompileResult {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(Diagnostic::error("E0109", "mismatched types")
+            .with_span(span)
+            .with_label(format!("expected `{}`, found `{}`", expected, found))
+            .into())
+    }
+}
+
+// Splits a `name:type` annotation, names without one are `i64`
+pub(crate) fn split_annotation(symbol: &str, span: Span) -> CompileResult<(&str, Type)> {
+    match symbol.split_once(':') {
+        None => Ok((symbol, Type::DEFAULT)),
+        Some((name, ty)) => match Type::from_name(ty) {
+            Some(ty) => Ok((name, ty)),
+            None => Err(Diagnostic::error("E0110", format!("unknown type `{}`", ty))
+                .with_span(span)
+                .with_label("not a type")
+                .with_help(
+                    "types are i8, i16, i32, i64, u64, f64, bool, str and functions \
+                     like `i64,i64->bool`",
+                )
+                .into()),
+        },
+    }
+}
+
+// Name and signature of the function defined by a `(def name (params) body)`
+// form, so it can be called before the form is compiled. Malformed forms are
+// left for `compile_define` to report.
+pub(crate) fn declared_function(expression: &Expression) -> Option<(String, Signature)> {
+    let items = match &expression.kind {
+        ExpressionKind::List(items) => items,
+        _ => return None,
+    };
+    match items.as_slice() {
+        [head, name, params, ..] => match (&head.kind, &name.kind, &params.kind) {
+            (
+                ExpressionKind::Symbol(def),
+                ExpressionKind::Symbol(name),
+                ExpressionKind::List(params),
+            ) if def == "def" => {
+                let (name, result) = split_annotation(name, expression.span).ok()?;
+                let params = params
+                    .iter()
+                    .map(|param| match &param.kind {
+                        ExpressionKind::Symbol(name) => {
+                            split_annotation(name, param.span).ok().map(|(_, ty)| ty)
+                        }
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<Type>>>()?;
+                Some((name.to_string(), Signature { params, result }))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Forms a module can hold, defining something
+pub(crate) const DEFINITIONS: &[&str] = &["def", "defvar", "defconst"];
+
+// Kind of definition a top level form is, e.g. `def`, or None when it
+// isn't one
+pub(crate) fn definition_kind(expression: &Expression) -> Option<&'static str> {
+    match &expression.kind {
+        ExpressionKind::List(items) => match items.first().map(|head| &head.kind) {
+            Some(ExpressionKind::Symbol(head)) => DEFINITIONS
+                .iter()
+                .find(|definition| *definition == head)
+                .copied(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Error for a global reusing the name of another global or function
+pub(crate) fn defined_twice(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::error("E0119", format!("`{}` is defined more than once", name))
+        .with_span(span)
+        .with_label("already defined in this module")
+}
+
+// Error for a top level form other than a definition
+pub(crate) fn not_a_definition(span: Span) -> Diagnostic {
+    Diagnostic::error("E0118", "expected a definition")
+        .with_span(span)
+        .with_label("not allowed at the top level")
+        .with_help("move it into the body of a function, such as `main`")
+}
+
+// Value of a global, computed at compile time
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    // Integer, with no type yet when it only comes from untyped literals
+    Int(i128, Option<IntType>),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl Value {
+    fn float(&self) -> f64 {
+        match self {
+            Value::Int(int, _) => *int as f64,
+            Value::Float(float) => *float,
+            _ => unreachable!("checked to be a number"),
+        }
+    }
+
+    fn ty(&self) -> Type {
+        match self {
+            Value::Int(_, int) => Type::Int(int.unwrap_or(IntType::I64)),
+            Value::Float(_) => Type::Float,
+            Value::Bool(_) => Type::Bool,
+            Value::Str(_) => Type::Str,
+        }
+    }
+}
+
+// Global defined by a `(defvar name value)` or `(defconst name value)` form
+pub(crate) struct Global<'a> {
+    pub name: &'a str,
+    pub ty: Type,
+    pub value: Value,
+    pub constant: bool,
+}
+
+// Splits a global definition, computing its value. `constants` are the
+// values of the constants defined before it, which it can refer to.
+pub(crate) fn split_global<'a>(
+    kind: &str,
+    args: &'a [Expression],
+    span: Span,
+    constants: &HashMap<String, Value>,
+) -> CompileResult<Global<'a>> {
+    if args.len() != 2 {
+        return Err(arity_error(kind, "2", args.len(), span).into());
+    }
+    let symbol = match &args[0].kind {
+        ExpressionKind::Symbol(symbol) => symbol,
+        _ => {
+            return Err(Diagnostic::error("E0103", format!("malformed {}", kind))
+                .with_span(args[0].span)
+                .with_label("expected a name")
+                .into())
+        }
+    };
+    let (name, ty) = split_annotation(symbol, args[0].span)?;
+    let value = evaluate(&args[1], constants)?;
+    let annotated = symbol.contains(':');
+    let value = match (value, &ty) {
+        (Value::Int(int, None), Type::Int(int_type)) if annotated => {
+            Value::Int(int, Some(*int_type))
+        }
+        (Value::Int(int, None), _) => Value::Int(int, Some(IntType::I64)),
+        (value, _) => value,
+    };
+    if annotated {
+        expect_type(&ty, &value.ty(), args[1].span)?;
+    }
+    if let Value::Int(int, Some(int_type)) = value {
+        if !int_type.fits(int) {
+            return Err(Diagnostic::error(
+                "E0111",
+                format!("value out of range for `{}`", int_type),
+            )
+            .with_span(args[1].span)
+            .with_label(format!("does not fit in `{}`", int_type))
+            .into());
+        }
+    }
+    Ok(Global {
+        name,
+        ty: value.ty(),
+        value,
+        constant: kind == "defconst",
+    })
+}
+
+// Computes the initializer of a global, made of literals, constants and
+// arithmetic on them
+fn evaluate(expression: &Expression, constants: &HashMap<String, Value>) -> CompileResult<Value> {
+    let not_constant = || {
+        Diagnostic::error("E0117", "initializer is not a constant")
+            .with_span(expression.span)
+            .with_label("not computable at compile time")
+            .with_help("use literals, constants defined before and `+`, `-`, `*` or `/`")
+    };
+    let (operator, args) = match &expression.kind {
+        ExpressionKind::Integer(int, ty) => return Ok(Value::Int(*int as i128, *ty)),
+        ExpressionKind::Float(float) => return Ok(Value::Float(*float)),
+        ExpressionKind::Boolean(boolean) => return Ok(Value::Bool(*boolean)),
+        ExpressionKind::String(string) => return Ok(Value::Str(string.clone())),
+        ExpressionKind::Symbol(name) => {
+            return constants
+                .get(name)
+                .cloned()
+                .ok_or_else(|| not_constant().into())
+        }
+        ExpressionKind::List(items) => match items.split_first() {
+            Some((
+                Expression {
+                    kind: ExpressionKind::Symbol(operator),
+                    ..
+                },
+                args,
+            )) if ["+", "-", "*", "/"].contains(&operator.as_str()) => (operator.as_str(), args),
+            _ => return Err(not_constant().into()),
+        },
+    };
+    let mut values = args
+        .iter()
+        .map(|arg| evaluate(arg, constants))
+        .collect::<CompileResult<Vec<Value>>>()?;
+    // The same arities as the operations compiled to code
+    let identity = Value::Int(if matches!(operator, "+" | "-") { 0 } else { 1 }, None);
+    match (operator, values.len()) {
+        ("+", 0) | ("*", 0) => return Ok(identity),
+        (_, 0) => return Err(arity_error(operator, "at least 1", 0, expression.span).into()),
+        ("-", 1) | ("/", 1) => values.insert(0, identity),
+        _ => {}
+    }
+    let mut values = values.into_iter();
+    let first = values.next().unwrap();
+    values.try_fold(first, |left, right| {
+        apply(operator, left, right, expression.span)
+    })
+}
+
+// `left operator right` for the arithmetic of initializers
+fn apply(operator: &str, left: Value, right: Value, span: Span) -> CompileResult<Value> {
+    expect_number(&left.ty(), span)?;
+    expect_number(&right.ty(), span)?;
+    match (left, right) {
+        (Value::Int(left, left_type), Value::Int(right, right_type)) => {
+            if let (Some(left_type), Some(right_type)) = (left_type, right_type) {
+                expect_type(&Type::Int(left_type), &Type::Int(right_type), span)?;
+            }
+            let result = match operator {
+                "+" => left.checked_add(right),
+                "-" => left.checked_sub(right),
+                "*" => left.checked_mul(right),
+                _ => left.checked_div(right),
+            };
+            result
+                .map(|result| Value::Int(result, left_type.or(right_type)))
+                .ok_or_else(|| {
+                    Diagnostic::error("E0117", "initializer is not a constant")
+                        .with_span(span)
+                        .with_label("overflows or divides by zero")
+                        .into()
+                })
+        }
+        (left, right) => {
+            let (left, right) = (left.float(), right.float());
+            Ok(Value::Float(match operator {
+                "+" => left + right,
+                "-" => left - right,
+                "*" => left * right,
+                _ => left / right,
+            }))
+        }
+    }
+}
+
+// Value of an integer literal written without a type suffix, which takes
+// whatever integer type the context needs
+pub(crate) fn untyped_integer(expression: &Expression) -> Option<i64> {
+    match expression.kind {
+        ExpressionKind::Integer(value, None) => Some(value),
+        _ => None,
+    }
+}
+
+// Error unless an untyped integer literal fits in the type it's used as
+pub(crate) fn check_literal(value: i64, int: IntType, span: Span) -> CompileResult {
+    if int.fits(value as i128) {
+        Ok(())
+    } else {
+        Err(
+            Diagnostic::error("E0111", format!("literal out of range for `{}`", int))
+                .with_span(span)
+                .with_label(format!("does not fit in `{}`", int))
+                .into(),
+        )
+    }
+}
+
+// Error unless `found` is a numeric type
+pub(crate) fn expect_number(found: &Type, span: Span) -> CompileResult {
+    match found {
+        Type::Int(_) | Type::Float => Ok(()),
+        _ => Err(Diagnostic::error("E0109", "mismatched types")
+            .with_span(span)
+            .with_label(format!("expected a number, found `{}`", found))
+            .into()),
+    }
+}
+
+// Type both operands of an arithmetic operation are promoted to: integers
+// are converted to floats when mixed with them, but integers of different
+// types are never mixed
+pub(crate) fn promoted_type(left: &Type, right: &Type, span: Span) -> CompileResult<Type> {
+    match (left, right) {
+        (Type::Float, _) | (_, Type::Float) => Ok(Type::Float),
+        _ => {
+            expect_type(left, right, span)?;
+            Ok(left.clone())
+        }
+    }
+}
+
+// A literal an operation starts from
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Constant {
+    Integer(i64),
+    Boolean(bool),
+}
+
+// How an operation treats its number of operands
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Arity {
+    // Any number of operands folded left to right, `(op)` is the constant
+    Fold(Constant),
+    // At least one operand, `(op x)` is `(op constant x)`
+    Inverse(Constant),
+    // At least one operand, each compared with the next one
+    Chain,
+    // Exactly two operands
+    Binary,
+}
+
+// Operands an operation is actually applied to: `(op)` becomes its identity
+// and `(op x)` of an inverse operation becomes `(op identity x)`
+pub(crate) fn operands(
+    name: &str,
+    arity: Arity,
+    args: &[Expression],
+    span: Span,
+) -> CompileResult<Vec<Expression>> {
+    let constant = |constant: Constant| {
+        let kind = match constant {
+            Constant::Integer(value) => ExpressionKind::Integer(value, None),
+            Constant::Boolean(value) => ExpressionKind::Boolean(value),
+        };
+        Expression::new(kind, span)
+    };
+    match (arity, args.len()) {
+        (Arity::Fold(identity), 0) => Ok(vec![constant(identity)]),
+        (Arity::Inverse(_), 0) | (Arity::Chain, 0) => {
+            Err(arity_error(name, "at least 1", 0, span).into())
+        }
+        (Arity::Inverse(identity), 1) => Ok(vec![constant(identity), args[0].clone()]),
+        (Arity::Binary, found) if found != 2 => Err(arity_error(name, "2", found, span).into()),
+        _ => Ok(args.to_vec()),
+    }
+}
+
+// Forms whose last expression is in tail position when the form itself is
+pub(crate) const TAIL_FORMS: &[&str] = &[
+    "if", "cond", "when", "unless", "begin", "progn", "let", "let*", "letrec",
+];
+
+// What an expression in tail position is the last expression of
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Tail {
+    // Body of a function with this signature, returning the value
+    Function(Signature),
+    // Body of the named let loop with this name, which calls jump back to
+    Loop(String),
+}
+
+// Forms evaluating one of two bodies depending on a test
+pub(crate) const CONDITIONALS: &[&str] = &["if", "cond", "when", "unless"];
+
+// Test of a conditional form and the bodies evaluated when it's true and
+// when it's false. At most one of them is missing, i.e. empty, and evaluates
+// to the zero value of the type of the other one.
+pub(crate) struct Conditional {
+    pub test: Expression,
+    pub then: Vec<Expression>,
+    pub otherwise: Vec<Expression>,
+}
+
+impl Conditional {
+    // Bodies in the order they're compiled, the one present first, and
+    // whether that one is evaluated when the test is false
+    pub(crate) fn bodies(&self) -> (&[Expression], &[Expression], bool) {
+        if self.then.is_empty() {
+            (&self.otherwise, &self.then, true)
+        } else {
+            (&self.then, &self.otherwise, false)
+        }
+    }
+}
+
+pub(crate) fn split_conditional(
+    name: &str,
+    args: &[Expression],
+    span: Span,
+) -> CompileResult<Conditional> {
+    let conditional =
+        |test: &Expression, then: &[Expression], otherwise: &[Expression]| Conditional {
+            test: test.clone(),
+            then: then.to_vec(),
+            otherwise: otherwise.to_vec(),
+        };
+    match (name, args) {
+        ("if", [test, then]) => Ok(conditional(test, std::slice::from_ref(then), &[])),
+        ("if", [test, then, otherwise]) => Ok(conditional(
+            test,
+            std::slice::from_ref(then),
+            std::slice::from_ref(otherwise),
+        )),
+        ("if", _) => Err(arity_error(name, "2 or 3", args.len(), span).into()),
+        ("when", [test, body @ ..]) if !body.is_empty() => Ok(conditional(test, body, &[])),
+        ("unless", [test, body @ ..]) if !body.is_empty() => Ok(conditional(test, &[], body)),
+        ("when" | "unless", _) => Err(arity_error(name, "at least 2", args.len(), span).into()),
+        _ => split_cond(args, span),
+    }
+}
+
+// `(cond (test body...)... (else body...))` is an `if` of its first clause,
+// evaluating a `cond` of the other clauses when the test is false
+fn split_cond(args: &[Expression], span: Span) -> CompileResult<Conditional> {
+    let (clause, rest) = args
+        .split_first()
+        .ok_or_else(|| arity_error("cond", "at least 1", 0, span))?;
+    let (test, then) = split_clause(clause)?;
+    let test = if !is_else(clause) {
+        test.clone()
+    } else if rest.is_empty() {
+        Expression::new(ExpressionKind::Boolean(true), test.span)
+    } else {
+        return Err(malformed_clause(clause.span, "`else` must be the last clause").into());
+    };
+    let otherwise = match rest {
+        [] => Vec::new(),
+        [last] if is_else(last) => split_clause(last)?.1.to_vec(),
+        _ => {
+            let head = Expression::new(ExpressionKind::Symbol("cond".to_string()), rest[0].span);
+            let mut cond = vec![head];
+            cond.extend_from_slice(rest);
+            let span = rest[0].span.to(rest[rest.len() - 1].span);
+            vec![Expression::new(ExpressionKind::List(cond), span)]
+        }
+    };
+    Ok(Conditional {
+        test,
+        then: then.to_vec(),
+        otherwise,
+    })
+}
+
+// Test and body of a `cond` clause
+fn split_clause(clause: &Expression) -> CompileResult<(&Expression, &[Expression])> {
+    match &clause.kind {
+        ExpressionKind::List(items) if items.len() >= 2 => Ok((&items[0], &items[1..])),
+        _ => Err(malformed_clause(clause.span, "expected `(test body...)`").into()),
+    }
+}
+
+fn is_else(clause: &Expression) -> bool {
+    match &clause.kind {
+        ExpressionKind::List(items) => {
+            matches!(items.first().map(|item| &item.kind), Some(ExpressionKind::Symbol(head)) if head == "else")
+        }
+        _ => false,
+    }
+}
+
+fn malformed_clause(span: Span, label: &str) -> Diagnostic {
+    Diagnostic::error("E0120", "malformed cond clause")
+        .with_span(span)
+        .with_label(label)
+}
+
+// Literal of the zero value of `ty`, which a missing body of a conditional
+// evaluates to. Functions have none.
+pub(crate) fn zero(ty: &Type, span: Span) -> CompileResult<Expression> {
+    let kind = match ty {
+        Type::Int(int) => ExpressionKind::Integer(0, Some(*int)),
+        Type::Float => ExpressionKind::Float(0.0),
+        Type::Bool => ExpressionKind::Boolean(false),
+        Type::Str => ExpressionKind::String(String::new()),
+        Type::Function(_) => {
+            return Err(Diagnostic::error(
+                "E0121",
+                "missing branch of a conditional evaluating to a function",
+            )
+            .with_span(span)
+            .with_label("a function has no zero value to evaluate to otherwise")
+            .with_help("give the conditional both branches")
+            .into())
+        }
+    };
+    Ok(Expression::new(kind, span))
+}
+
+// Which bindings of a `let` form the value of a binding can refer to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LetKind {
+    // `let`: none, they're all bound after every value is computed
+    Parallel,
+    // `let*`: the ones before it
+    Sequential,
+    // `letrec`: all of them, though only those before it have a value yet
+    Recursive,
+}
+
+impl LetKind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            LetKind::Parallel => "let",
+            LetKind::Sequential => "let*",
+            LetKind::Recursive => "letrec",
+        }
+    }
+}
+
+// Name, optional type annotation and value of a binding in a `let` form
+pub(crate) struct Binding<'a> {
+    pub name: &'a str,
+    pub ty: Option<Type>,
+    pub value: &'a Expression,
+}
+
+// Name, value type, bindings and body of a named let
+pub(crate) type NamedLet<'a> = (&'a str, Type, Vec<Binding<'a>>, &'a [Expression]);
+
+// Splits a `(let ((name value) ...) body ...)` form into its bindings and the
+// expressions of its body
+pub(crate) fn split_let(
+    kind: LetKind,
+    args: &[Expression],
+    span: Span,
+) -> CompileResult<(Vec<Binding<'_>>, &[Expression])> {
+    if args.len() < 2 {
+        return Err(arity_error(kind.name(), "at least 2", args.len(), span).into());
+    }
+    let items = match &args[0].kind {
+        ExpressionKind::List(items) => items,
+        _ => {
+            return Err(Diagnostic::error(
+                "E0112",
+                format!("malformed bindings in {}", kind.name()),
+            )
+            .with_span(args[0].span)
+            .with_label("expected a list of bindings")
+            .into())
+        }
+    };
+    let mut bindings: Vec<Binding> = Vec::with_capacity(items.len());
+    for item in items {
+        let binding = split_binding(kind.name(), item)?;
+        if bindings.iter().any(|bound| bound.name == binding.name) {
+            return Err(Diagnostic::error(
+                "E0112",
+                format!(
+                    "`{}` is bound more than once in {}",
+                    binding.name,
+                    kind.name()
+                ),
+            )
+            .with_span(item.span)
+            .with_label("already bound")
+            .into());
+        }
+        bindings.push(binding);
+    }
+
+    // The values of letrec bindings are computed in order, so one can't use
+    // a binding computed after it, save in the body of a lambda, which only
+    // runs once they all are
+    if kind == LetKind::Recursive {
+        for (i, binding) in bindings.iter().enumerate() {
+            for later in &bindings[i..] {
+                if let Some(span) = mention(binding.value, later.name, false) {
+                    return Err(Diagnostic::error(
+                        "E0113",
+                        format!("`{}` is used before it's initialized", later.name),
+                    )
+                    .with_span(span)
+                    .with_label("not initialized yet")
+                    .into());
+                }
+            }
+        }
+    }
+    Ok((bindings, &args[1..]))
+}
+
+// Names of letrec bindings a lambda in their own value or that of an earlier
+// binding refers to, before they're bound
+pub(crate) fn forward_references<'a>(bindings: &[Binding<'a>]) -> Vec<&'a str> {
+    bindings
+        .iter()
+        .enumerate()
+        .filter(|(i, later)| {
+            bindings[..=*i]
+                .iter()
+                .any(|binding| mention(binding.value, later.name, true).is_some())
+        })
+        .map(|(_, later)| later.name)
+        .collect()
+}
+
+// List of params of a `(lambda (params) body ...)` form, None for other
+// expressions
+pub(crate) fn lambda_params(expression: &Expression) -> Option<&Expression> {
+    match &expression.kind {
+        ExpressionKind::List(items) => match items.as_slice() {
+            [head, params, _, ..] if is_lambda(head) => Some(params),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_lambda(head: &Expression) -> bool {
+    matches!(&head.kind, ExpressionKind::Symbol(name) if name == "lambda" || name == "fn")
+}
+
+// Splits a `(name value)` binding of the form `form`
+fn split_binding<'a>(form: &str, item: &'a Expression) -> CompileResult<Binding<'a>> {
+    let malformed = |span: Span| {
+        Diagnostic::error("E0112", format!("malformed binding in {}", form))
+            .with_span(span)
+            .with_label("expected `(name value)`")
+    };
+    let (symbol, value) = match &item.kind {
+        ExpressionKind::List(pair) if pair.len() == 2 => match &pair[0].kind {
+            ExpressionKind::Symbol(symbol) => (symbol, &pair[1]),
+            _ => return Err(malformed(pair[0].span).into()),
+        },
+        _ => return Err(malformed(item.span).into()),
+    };
+    let (name, ty) = split_annotation(symbol, item.span)?;
+    Ok(Binding {
+        name,
+        ty: Some(ty).filter(|_| symbol.contains(':')),
+        value,
+    })
+}
+
+// Splits a `(let name ((name value) ...) body ...)` loop into its name, the
+// type of its value, its bindings and its body. None for other let forms.
+pub(crate) fn split_named_let(
+    args: &[Expression],
+    span: Span,
+) -> CompileResult<Option<NamedLet<'_>>> {
+    let symbol = match args.first().map(|arg| &arg.kind) {
+        Some(ExpressionKind::Symbol(symbol)) => symbol,
+        _ => return Ok(None),
+    };
+    if args.len() < 3 {
+        return Err(arity_error("let", "at least 3", args.len(), span).into());
+    }
+    let (name, result) = split_annotation(symbol, args[0].span)?;
+    let (bindings, body) = split_let(LetKind::Parallel, &args[1..], span)?;
+    Ok(Some((name, result, bindings, body)))
+}
+
+// Splits a `(dotimes (name count) body ...)` form into the binding of its
+// counter and its body
+pub(crate) fn split_dotimes(
+    args: &[Expression],
+    span: Span,
+) -> CompileResult<(Binding<'_>, &[Expression])> {
+    match args.split_first() {
+        Some((counter, body)) => Ok((split_binding("dotimes", counter)?, body)),
+        None => Err(arity_error("dotimes", "at least 1", 0, span).into()),
+    }
+}
+
+// Whether any of the expressions assigns `name` with `set!`
+pub(crate) fn assigns(expressions: &[Expression], name: &str) -> bool {
+    expressions.iter().any(|expression| match &expression.kind {
+        ExpressionKind::List(items) => {
+            let assigned = match items.as_slice() {
+                [head, target, ..] => {
+                    matches!(&head.kind, ExpressionKind::Symbol(set) if set == "set!")
+                        && matches!(&target.kind, ExpressionKind::Symbol(target) if target == name)
+                }
+                _ => false,
+            };
+            assigned || assigns(items, name)
+        }
+        _ => false,
+    })
+}
+
+// Error for a `set!` of a name that isn't an assignable variable, being
+// `what` instead
+pub(crate) fn cannot_assign(name: &str, what: &str, span: Span) -> Diagnostic {
+    Diagnostic::error("E0116", format!("cannot assign to `{}`", name))
+        .with_span(span)
+        .with_label(format!("`{}` is {}", name, what))
+}
+
+// What a variable `set!` can't assign is, when it's neither a function nor a
+// constant
+pub(crate) const UNASSIGNABLE: &str = "bound by a named let, dotimes or closure";
+
+// Name assigned by a `(set! name value)` form
+pub(crate) fn split_set(args: &[Expression], span: Span) -> CompileResult<(&str, &Expression)> {
+    if args.len() != 2 {
+        return Err(arity_error("set!", "2", args.len(), span).into());
+    }
+    match &args[0].kind {
+        ExpressionKind::Symbol(name) => Ok((name, &args[1])),
+        _ => Err(Diagnostic::error("E0116", "malformed set!")
+            .with_span(args[0].span)
+            .with_label("expected a variable name")
+            .into()),
+    }
+}
+
+// Span of a reference to `name` in an expression, either in the body of a
+// lambda in it when `delayed`, or outside of them
+fn mention(expression: &Expression, name: &str, delayed: bool) -> Option<Span> {
+    match &expression.kind {
+        ExpressionKind::Symbol(symbol) if symbol == name && !delayed => Some(expression.span),
+        ExpressionKind::List(items) => match items.split_first() {
+            Some((head, _)) if is_lambda(head) && !delayed => None,
+            Some((head, body)) if is_lambda(head) => body
+                .iter()
+                .find_map(|item| mention(item, name, false).or_else(|| mention(item, name, true))),
+            _ => items.iter().find_map(|item| mention(item, name, delayed)),
+        },
+        _ => None,
+    }
+}
+
+// Error for a call to a named let loop other than to start it over
+pub(crate) fn loop_outside_tail_position(name: &str, span: Span) -> Diagnostic {
+    Diagnostic::error(
+        "E0115",
+        format!("loop `{}` is called outside tail position", name),
+    )
+    .with_span(span)
+    .with_label("not the last expression of the loop body")
+    .with_help("a named let can only call itself to start over")
+}
+
+pub(crate) fn expected_integer(found: &Type, span: Span) -> Diagnostic {
+    Diagnostic::error("E0109", "mismatched types")
+        .with_span(span)
+        .with_label(format!("expected an integer, found `{}`", found))
+}
+
+// Error for a special form or primitive called with the wrong number of
+// arguments
+pub(crate) fn arity_error(name: &str, expected: &str, found: usize, span: Span) -> Diagnostic {
+    Diagnostic::error(
+        "E0106",
+        format!(
+            "`{}` takes {} argument{}, found {}",
+            name,
+            expected,
+            if expected.ends_with(" 1") || expected == "1" {
+                ""
+            } else {
+                "s"
+            },
+            found
+        ),
+    )
+    .with_span(span)
+    .with_label("wrong number of arguments")
+}
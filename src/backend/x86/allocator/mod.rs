@@ -0,0 +1,206 @@
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+
+// Registers virtual registers are allocated to. Only callee saved registers
+// are used, so values survive calls, and the caller saved ones are left to
+// the fixed instruction sequences of the code generator.
+const ALLOCATABLE_REGISTERS: &[&str] = &["rbx", "r12", "r13", "r14", "r15"];
+
+// Register used to move a value between two spilled virtual registers
+const SCRATCH_REGISTER: &str = "r11";
+
+const VIRTUAL_PREFIX: &str = "%v";
+
+// Name of the nth virtual register in the code of a function
+pub(crate) fn virtual_register(n: usize) -> String {
+    format!("{}{}", VIRTUAL_PREFIX, n)
+}
+
+// Code of a function with its virtual registers replaced
+#[derive(Debug, PartialEq)]
+pub(crate) struct Allocation {
+    pub code: Vec<String>,
+    // Callee saved registers the code uses, to be preserved by the function
+    pub saved: Vec<&'static str>,
+    // Stack slots taken by spilled virtual registers
+    pub spilled: usize,
+}
+
+// Where a virtual register lives
+#[derive(Clone, Copy, Debug)]
+enum Location {
+    Register(&'static str),
+    // Stack slot, the first one is just below the saved rbp
+    Slot(usize),
+}
+
+// Lines of code where a virtual register is live
+#[derive(Clone, Copy, Debug)]
+struct Interval {
+    register: usize,
+    start: usize,
+    end: usize,
+}
+
+// Replaces the virtual registers in the code of a function with callee saved
+// registers, by linear scan over their live intervals, or with stack slots
+// when registers run out. Only a `mov` between two virtual registers may end
+// up with both operands in memory.
+pub(crate) fn allocate(code: &[String]) -> Allocation {
+    let mut intervals = live_intervals(code);
+    intervals.sort_by_key(|interval| (interval.start, interval.register));
+
+    let mut locations = HashMap::new();
+    let mut active: Vec<Interval> = Vec::new();
+    let mut free: Vec<&'static str> = ALLOCATABLE_REGISTERS.iter().rev().copied().collect();
+    let mut saved = Vec::new();
+    let mut spilled = 0;
+    for interval in intervals {
+        // Registers of intervals that ended are free again
+        active.retain(|previous| {
+            if previous.end < interval.start {
+                if let Some(Location::Register(register)) = locations.get(&previous.register) {
+                    free.push(*register);
+                }
+                false
+            } else {
+                true
+            }
+        });
+
+        if let Some(register) = free.pop() {
+            if !saved.contains(&register) {
+                saved.push(register);
+            }
+            locations.insert(interval.register, Location::Register(register));
+            active.push(interval);
+            continue;
+        }
+
+        // Spill whichever interval ends last, keeping registers for the
+        // values needed soonest
+        spilled += 1;
+        let slot = Location::Slot(spilled);
+        let last = (0..active.len()).max_by_key(|i| active[*i].end);
+        match last {
+            Some(i) if active[i].end > interval.end => {
+                let register = locations[&active[i].register];
+                locations.insert(active[i].register, slot);
+                locations.insert(interval.register, register);
+                active[i] = interval;
+            }
+            _ => {
+                locations.insert(interval.register, slot);
+            }
+        }
+    }
+
+    let code = code
+        .iter()
+        .flat_map(|line| rewrite(line, &locations))
+        .collect();
+    saved.sort_by_key(|register| ALLOCATABLE_REGISTERS.iter().position(|r| r == register));
+    Allocation {
+        code,
+        saved,
+        spilled,
+    }
+}
+
+// First and last line where each virtual register appears. A value live at
+// the start of a loop, i.e. at a label jumped to from below, is kept live
+// until the jump.
+fn live_intervals(code: &[String]) -> Vec<Interval> {
+    let mut intervals: HashMap<usize, Interval> = HashMap::new();
+    let mut labels = HashMap::new();
+    for (line, text) in code.iter().enumerate() {
+        if let Some(label) = text.trim().strip_suffix(':') {
+            labels.insert(label.to_string(), line);
+        }
+        for register in virtual_registers(text) {
+            let interval = intervals.entry(register).or_insert(Interval {
+                register,
+                start: line,
+                end: line,
+            });
+            interval.end = line;
+        }
+    }
+
+    let loops: Vec<(usize, usize)> = code
+        .iter()
+        .enumerate()
+        .filter_map(|(line, text)| {
+            let mut words = text.split_whitespace();
+            let jump = words.next()?;
+            let target = labels.get(words.next()?)?;
+            if jump.starts_with('j') && *target < line {
+                Some((*target, line))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for interval in intervals.values_mut() {
+            for (start, end) in &loops {
+                if interval.start < *start && interval.end >= *start && interval.end < *end {
+                    interval.end = *end;
+                    changed = true;
+                }
+            }
+        }
+    }
+    intervals.into_values().collect()
+}
+
+// Numbers of the virtual registers in a line of code
+fn virtual_registers(text: &str) -> Vec<usize> {
+    text.match_indices(VIRTUAL_PREFIX)
+        .filter_map(|(i, _)| {
+            let digits: String = text[i + VIRTUAL_PREFIX.len()..]
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect();
+            digits.parse().ok()
+        })
+        .collect()
+}
+
+// Replaces the virtual registers of a line, turning it into the lines doing
+// the same with their locations. Moves from a location to itself are dropped.
+fn rewrite(text: &str, locations: &HashMap<usize, Location>) -> Vec<String> {
+    let mut rewritten = text.to_string();
+    let mut registers = virtual_registers(text);
+    // Longest names first, so `%v1` doesn't replace part of `%v12`
+    registers.sort_by_key(|register| std::cmp::Reverse(*register));
+    for register in registers {
+        let location = match locations[&register] {
+            Location::Register(name) => name.to_string(),
+            Location::Slot(slot) => format!("qword [rbp - {}]", 8 * slot),
+        };
+        rewritten = rewritten.replace(&virtual_register(register), &location);
+    }
+
+    let instruction = rewritten.trim_start();
+    if let Some((destination, source)) = instruction
+        .strip_prefix("mov ")
+        .and_then(|operands| operands.split_once(", "))
+    {
+        if destination == source {
+            return vec![];
+        }
+        if destination.contains('[') && source.contains('[') {
+            let indent = &rewritten[..rewritten.len() - instruction.len()];
+            return vec![
+                format!("{}mov {}, {}", indent, SCRATCH_REGISTER, source),
+                format!("{}mov {}, {}", indent, destination, SCRATCH_REGISTER),
+            ];
+        }
+    }
+    vec![rewritten]
+}
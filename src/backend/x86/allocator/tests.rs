@@ -0,0 +1,73 @@
+use super::*;
+
+fn lines(code: &str) -> Vec<String> {
+    code.lines().map(str::to_string).collect()
+}
+
+#[test]
+fn allocate_reuses_registers_of_dead_values() {
+    let allocation = allocate(&lines(
+        "\tmov %v1, rdi
+\tmov rax, %v1
+\tmov %v2, rax
+\tmov rax, %v2",
+    ));
+    assert_eq!(
+        allocation,
+        Allocation {
+            code: lines(
+                "\tmov rbx, rdi
+\tmov rax, rbx
+\tmov rbx, rax
+\tmov rax, rbx"
+            ),
+            saved: vec!["rbx"],
+            spilled: 0,
+        }
+    );
+}
+
+#[test]
+fn allocate_spills_the_value_needed_last() {
+    let mut code: Vec<String> = (1..=6)
+        .map(|n| format!("\tmov {}, rax", virtual_register(n)))
+        .collect();
+    // %v1 is needed last, so it's the one kept in memory
+    code.extend((2..=6).map(|n| format!("\tmov rax, {}", virtual_register(n))));
+    code.push("\tmov rax, %v1".to_string());
+
+    let allocation = allocate(&code);
+    assert_eq!(allocation.spilled, 1);
+    assert_eq!(allocation.saved, ALLOCATABLE_REGISTERS);
+    assert_eq!(allocation.code[0], "\tmov qword [rbp - 8], rax");
+    // The last value gets the register %v1 had
+    assert_eq!(allocation.code[5], "\tmov rbx, rax");
+    assert_eq!(allocation.code[11], "\tmov rax, qword [rbp - 8]");
+}
+
+#[test]
+fn rewrite_moves_between_slots_through_a_register() {
+    let locations = vec![(1, Location::Slot(1)), (2, Location::Slot(2))]
+        .into_iter()
+        .collect();
+    assert_eq!(
+        rewrite("\tmov %v2, %v1", &locations),
+        lines("\tmov r11, qword [rbp - 8]\n\tmov qword [rbp - 16], r11")
+    );
+    assert!(rewrite("\tmov %v1, %v1", &locations).is_empty());
+}
+
+#[test]
+fn allocate_keeps_values_live_through_loops() {
+    let allocation = allocate(&lines(
+        "\tmov %v1, rdi
+.Lloop1:
+\tmov rax, %v1
+\tmov %v2, rax
+\tmov rax, %v2
+\tjmp .Lloop1",
+    ));
+    // %v1 is used again on the next iteration, so %v2 can't take its register
+    assert_eq!(allocation.code[3], "\tmov r12, rax");
+    assert_eq!(allocation.saved, vec!["rbx", "r12"]);
+}
@@ -0,0 +1,1786 @@
+use crate::backend::{
+    arity_error, cannot_assign, check_literal, declared_function, defined_twice, definition_kind,
+    expect_number, expect_type, expected_integer, loop_outside_tail_position, not_a_definition,
+    operands, promoted_type, run_tool, split_annotation, split_conditional, split_dotimes,
+    split_global, split_let, split_named_let, split_set, untyped_integer, zero, Arity, Backend,
+    CompileResult, Constant, Definition, LetKind, NamedLet, Param, Signature, Tail, Type, Value,
+    CONDITIONALS, TAIL_FORMS, UNASSIGNABLE,
+};
+use crate::diagnostics::Diagnostic;
+use crate::parser::{Expression, ExpressionKind, IntType, Span};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::process::Command;
+use std::rc::Rc;
+
+mod allocator;
+#[cfg(test)]
+mod tests;
+
+pub(crate) type Scope = HashMap<String, Variable>;
+
+// Where the value of a name lives, and its type
+#[derive(Clone, Debug)]
+pub(crate) struct Variable {
+    location: String,
+    ty: Type,
+    // Whether `set!` can assign it, i.e. it's a let binding or a param
+    assignable: bool,
+}
+
+type PrimitiveFunction =
+    Rc<dyn Fn(&mut X86, &[Expression], Span, Option<&str>, &mut Scope) -> CompileResult<Type>>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum OperationKind {
+    // Instruction taking rax and rcx, or xmm0 and xmm1 for floats
+    Arithmetic,
+    // Quotient of rax divided by rcx, or divsd for floats
+    Division,
+    // Remainder of rax divided by rcx, left in rdx by the division, or
+    // computed by fmod for floats
+    Remainder,
+    // Remainder with the sign of the divisor
+    Modulo,
+    // Flags of comparing rax with rcx, read with setcc
+    Comparison,
+    // Shifts rax by cl
+    Shift,
+}
+
+// Instructions implementing an operation on signed integers, unsigned
+// integers and floats. Comparisons hold condition codes instead, the float
+// ones being those of ucomisd. Operations on integers only have no float
+// instruction.
+#[derive(Clone, Copy)]
+struct Operation {
+    arity: Arity,
+    kind: OperationKind,
+    signed: &'static str,
+    unsigned: &'static str,
+    float: Option<&'static str>,
+    // Whether it also applies to bools
+    boolean: bool,
+}
+
+impl Operation {
+    const fn numeric(
+        kind: OperationKind,
+        arity: Arity,
+        signed: &'static str,
+        unsigned: &'static str,
+        float: &'static str,
+    ) -> Operation {
+        Operation {
+            arity,
+            kind,
+            signed,
+            unsigned,
+            float: Some(float),
+            boolean: false,
+        }
+    }
+
+    const fn integer(
+        kind: OperationKind,
+        arity: Arity,
+        signed: &'static str,
+        unsigned: &'static str,
+    ) -> Operation {
+        Operation {
+            arity,
+            kind,
+            signed,
+            unsigned,
+            float: None,
+            boolean: false,
+        }
+    }
+
+    const fn with_boolean(self) -> Operation {
+        Operation {
+            boolean: true,
+            ..self
+        }
+    }
+}
+
+const OPERATIONS: &[(&str, Operation)] = {
+    use Arity::*;
+    use Constant::*;
+    use OperationKind::*;
+    &[
+        (
+            "+",
+            Operation::numeric(Arithmetic, Fold(Integer(0)), "add", "add", "addsd"),
+        ),
+        (
+            "-",
+            Operation::numeric(Arithmetic, Inverse(Integer(0)), "sub", "sub", "subsd"),
+        ),
+        (
+            "*",
+            Operation::numeric(Arithmetic, Fold(Integer(1)), "imul", "imul", "mulsd"),
+        ),
+        (
+            "/",
+            Operation::numeric(Division, Inverse(Integer(1)), "idiv", "div", "divsd"),
+        ),
+        (
+            "rem",
+            Operation::numeric(Remainder, Binary, "idiv", "div", "fmod"),
+        ),
+        (
+            "mod",
+            Operation::numeric(Modulo, Binary, "idiv", "div", "fmod"),
+        ),
+        (
+            "=",
+            Operation::numeric(Comparison, Chain, "e", "e", "e").with_boolean(),
+        ),
+        (
+            "!=",
+            Operation::numeric(Comparison, Binary, "ne", "ne", "ne").with_boolean(),
+        ),
+        ("<", Operation::numeric(Comparison, Chain, "l", "b", "b")),
+        (
+            "<=",
+            Operation::numeric(Comparison, Chain, "le", "be", "be"),
+        ),
+        (">", Operation::numeric(Comparison, Chain, "g", "a", "a")),
+        (
+            ">=",
+            Operation::numeric(Comparison, Chain, "ge", "ae", "ae"),
+        ),
+        (
+            "and",
+            Operation::integer(Arithmetic, Fold(Boolean(true)), "and", "and").with_boolean(),
+        ),
+        (
+            "or",
+            Operation::integer(Arithmetic, Fold(Boolean(false)), "or", "or").with_boolean(),
+        ),
+        (
+            "xor",
+            Operation::integer(Arithmetic, Fold(Boolean(false)), "xor", "xor").with_boolean(),
+        ),
+        ("shl", Operation::integer(Shift, Binary, "shl", "shl")),
+        ("shr", Operation::integer(Shift, Binary, "sar", "shr")),
+    ]
+};
+
+// Where an operand is kept until the operation is applied to it
+enum Operand {
+    Register(String),
+    Immediate(i64),
+}
+
+// Registers passing the first integer and float arguments of a call, as the
+// System V AMD64 ABI says. The rest are passed on the stack.
+const PARAM_REGISTERS: &[&str] = &["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+const FLOAT_PARAM_REGISTERS: &[&str] = &[
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7",
+];
+
+// Stands for the epilogue of the function in its body, before a tail call.
+// What the epilogue restores is only known once registers are allocated.
+const EPILOGUE: &str = "; epilogue";
+
+// Named let loop, whose body starts over when it calls the loop
+struct Loop {
+    name: String,
+    // Label starting the loop
+    head: String,
+    bindings: Vec<Variable>,
+    result: Type,
+}
+
+struct X86 {
+    primitive_functions: HashMap<String, PrimitiveFunction>,
+    signatures: HashMap<String, Signature>,
+    output: RefCell<String>,
+    // Constant data, emitted in its own section after the code
+    rodata: Vec<String>,
+    // Constants holding an address, which the dynamic linker has to write
+    // before they're made read only, emitted after the constant data
+    relro: Vec<String>,
+    // Global variables, emitted after the constant data
+    data: Vec<String>,
+    // Values of the constants defined so far, which initializers can use
+    constants: HashMap<String, Value>,
+    // Number of labels generated so far, keeping them unique
+    labels: usize,
+    // Virtual registers used by the function being compiled
+    registers: usize,
+    // Words pushed since the frame of the function being compiled was set
+    // up, which tells how to keep the stack aligned at calls
+    depth: usize,
+    // Set while the next expression compiled is in tail position
+    tail: Option<Tail>,
+    // Named let loops being compiled, the innermost last
+    loops: Vec<Loop>,
+    // Name resolution errors, collected so compilation can keep going
+    errors: Vec<Diagnostic>,
+}
+
+impl X86 {
+    fn new() -> Self {
+        let primitive_functions = {
+            let mut m = HashMap::<String, PrimitiveFunction>::new();
+            m.insert("def".to_string(), Rc::new(X86::compile_define));
+            m.insert("module".to_string(), Rc::new(X86::compile_module));
+            m.insert("print".to_string(), Rc::new(X86::compile_print));
+            for name in CONDITIONALS {
+                m.insert(name.to_string(), X86::compile_conditional(name));
+            }
+            m.insert("while".to_string(), Rc::new(X86::compile_while));
+            m.insert("set!".to_string(), Rc::new(X86::compile_set));
+            m.insert("dotimes".to_string(), Rc::new(X86::compile_dotimes));
+            for name in &["lambda", "fn"] {
+                m.insert(name.to_string(), Rc::new(X86::compile_lambda));
+            }
+            for name in &["begin", "progn"] {
+                m.insert(name.to_string(), X86::compile_begin(name));
+            }
+            for kind in &[LetKind::Parallel, LetKind::Sequential, LetKind::Recursive] {
+                m.insert(kind.name().to_string(), X86::compile_let(*kind));
+            }
+            for (name, operation) in OPERATIONS {
+                m.insert(name.to_string(), X86::compile_operation(name, *operation));
+            }
+            for name in &["and", "or"] {
+                let (_, operation) = OPERATIONS.iter().find(|(op, _)| op == name).unwrap();
+                m.insert(name.to_string(), X86::compile_logic(name, *operation));
+            }
+            m
+        };
+        let output = RefCell::new(String::new());
+
+        X86 {
+            primitive_functions,
+            signatures: HashMap::new(),
+            output,
+            rodata: Vec::new(),
+            relro: Vec::new(),
+            data: Vec::new(),
+            constants: HashMap::new(),
+            labels: 0,
+            registers: 0,
+            depth: 0,
+            tail: None,
+            loops: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn emit_prefix(&mut self) {
+        self.emit(0, "; Generated with ulisp");
+        self.emit(0, ";");
+        self.emit(0, "; To compile run the following:");
+        self.emit(0, "; $ nasm -f elf64 program.asm");
+        self.emit(0, "; $ gcc -o program program.o");
+        self.emit(0, "");
+
+        self.emit(1, "global main");
+        self.emit(1, "extern printf");
+        self.emit(1, "extern puts");
+        self.emit(1, "extern fmod\n");
+
+        self.emit(1, "SECTION .text\n");
+    }
+
+    fn emit_postfix(&mut self) {
+        // Returning from main, rather than making the exit syscall, lets the
+        // C runtime flush stdout buffers filled by `print`
+        self.emit(0, "main:");
+        self.emit(1, "push rbp");
+        self.emit(1, "mov rbp, rsp");
+        self.emit(1, "call program_main");
+        self.emit(1, "pop rbp");
+        self.emit(1, "ret");
+
+        if !self.rodata.is_empty() {
+            self.emit(0, "");
+            self.emit(1, "SECTION .rodata\n");
+            for line in self.rodata.clone() {
+                self.emit(0, line);
+            }
+        }
+        if !self.relro.is_empty() {
+            self.emit(0, "");
+            self.emit(
+                1,
+                "SECTION .data.rel.ro progbits alloc noexec write align=8\n",
+            );
+            for line in self.relro.clone() {
+                self.emit(0, line);
+            }
+        }
+        if !self.data.is_empty() {
+            self.emit(0, "");
+            self.emit(1, "SECTION .data\n");
+            for line in self.data.clone() {
+                self.emit(0, line);
+            }
+        }
+    }
+
+    // Adds `value` as a null terminated string to the data section, returning
+    // its label
+    fn string_constant(&mut self, value: &str) -> String {
+        let label = format!("str{}", self.rodata.len());
+        let bytes: Vec<String> = value
+            .bytes()
+            .chain(std::iter::once(0))
+            .map(|b| b.to_string())
+            .collect();
+        self.rodata
+            .push(format!("{}: db {}", label, bytes.join(", ")));
+        label
+    }
+
+    // Writes a value to stdout followed by a newline, evaluating to 0
+    fn compile_print(
+        &mut self,
+        args: &[Expression],
+        span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        if args.len() != 1 {
+            return Err(arity_error("print", "1", args.len(), span).into());
+        }
+
+        let ty = self.compile_expression(&args[0], Some("rax"), scope)?;
+        match ty {
+            Type::Str => {
+                self.emit(1, "mov rdi, rax");
+                self.emit_libc_call("puts", 0);
+            }
+            Type::Bool => {
+                let true_text = self.string_constant("#t");
+                let false_text = self.string_constant("#f");
+                self.emit(1, format!("lea rdi, [rel {}]", false_text));
+                self.emit(1, format!("lea rsi, [rel {}]", true_text));
+                self.emit(1, "test rax, rax");
+                self.emit(1, "cmovnz rdi, rsi");
+                self.emit_libc_call("puts", 0);
+            }
+            Type::Int(int) => {
+                let format = if int.is_signed() { "%ld\n" } else { "%lu\n" };
+                let format = self.string_constant(format);
+                self.emit(1, format!("lea rdi, [rel {}]", format));
+                self.emit(1, "mov rsi, rax");
+                self.emit_libc_call("printf", 0);
+            }
+            Type::Float => {
+                let format = self.string_constant("%g\n");
+                self.emit(1, format!("lea rdi, [rel {}]", format));
+                self.emit(1, "movq xmm0, rax");
+                self.emit_libc_call("printf", 1);
+            }
+            Type::Function(_) => {
+                let text = self.string_constant("#<function>");
+                self.emit(1, format!("lea rdi, [rel {}]", text));
+                self.emit_libc_call("puts", 0);
+            }
+        }
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, 0", d));
+        }
+        Ok(Type::DEFAULT)
+    }
+
+    // Closures need the environment conversion of the LLVM backend, which
+    // this one doesn't do
+    fn compile_lambda(
+        &mut self,
+        _args: &[Expression],
+        span: Span,
+        _destination: Option<&str>,
+        _scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        Err(closures_unsupported(span).into())
+    }
+
+    // Local label, unique in the whole program, starting with `name`
+    fn label(&mut self, name: &str) -> String {
+        self.labels += 1;
+        format!(".L{}{}", name, self.labels)
+    }
+
+    // Evaluates one of the bodies of a conditional form depending on its
+    // test, the one compiled first giving the type of the other
+    fn compile_conditional(name: &'static str) -> PrimitiveFunction {
+        let c = move |backend: &mut X86,
+                      args: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let conditional = split_conditional(name, args, span)?;
+            let tail = backend.tail.take();
+            let else_label = backend.label("else");
+            let end_label = backend.label("endif");
+
+            let test = &conditional.test;
+            let test_ty = backend.compile_expression(test, Some("rax"), scope)?;
+            expect_type(&Type::Bool, &test_ty, test.span)?;
+            let (first, second, negated) = conditional.bodies();
+            backend.emit(1, "test rax, rax");
+            let jump = if negated { "jnz" } else { "jz" };
+            backend.emit(1, format!("{} {}", jump, else_label));
+
+            backend.tail = tail.clone();
+            let ty = backend.compile_body(first, None, scope)?;
+            backend.emit(1, format!("jmp {}", end_label));
+
+            // The other body, or the zero value when it's missing
+            backend.emit(0, format!("{}:", else_label));
+            backend.tail = tail;
+            if second.is_empty() {
+                backend.compile_typed(&zero(&ty, span)?, "rax", &ty, scope)?;
+            } else {
+                backend.compile_body(second, Some(ty.clone()), scope)?;
+            }
+
+            backend.emit(0, format!("{}:", end_label));
+            if let Some(d) = destination {
+                backend.emit(1, format!("mov {}, rax", d));
+            }
+            Ok(ty)
+        };
+        Rc::new(c)
+    }
+
+    // `and` or `or` of bools, only evaluating operands until one of them
+    // decides the result. Integers are combined bitwise by `operation`.
+    fn compile_logic(name: &'static str, operation: Operation) -> PrimitiveFunction {
+        let bitwise = X86::compile_operation(name, operation);
+        let c = move |backend: &mut X86,
+                      args: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let first = match args.first() {
+                Some(first) if untyped_integer(first).is_none() => first,
+                _ => return bitwise(backend, args, span, destination, scope),
+            };
+            let ty = backend.compile_expression(first, Some("rax"), scope)?;
+            if ty != Type::Bool {
+                return backend.fold_bitwise(name, &operation, ty, args, destination, scope);
+            }
+
+            // The operand deciding the result is left in rax as the result
+            let decisive = name == "or";
+            let end_label = backend.label(&format!("{}end", name));
+            for arg in &args[1..] {
+                backend.emit(1, "test rax, rax");
+                let jump = if decisive { "jnz" } else { "jz" };
+                backend.emit(1, format!("{} {}", jump, end_label));
+                let ty = backend.compile_expression(arg, Some("rax"), scope)?;
+                expect_type(&Type::Bool, &ty, arg.span)?;
+            }
+            backend.emit(0, format!("{}:", end_label));
+            if let Some(d) = destination {
+                backend.emit(1, format!("mov {}, rax", d));
+            }
+            Ok(Type::Bool)
+        };
+        Rc::new(c)
+    }
+
+    // Folds integer operands bitwise into the value of the first one, already
+    // compiled into rax. Untyped integer literals take its type.
+    fn fold_bitwise(
+        &mut self,
+        name: &str,
+        operation: &Operation,
+        ty: Type,
+        operands: &[Expression],
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        if operands.len() == 1 {
+            operand_type(name, operation, &ty, &ty, operands[0].span)?;
+        }
+        let mut ty = ty;
+        for operand in &operands[1..] {
+            let accumulator = self.virtual_register();
+            self.emit(1, format!("mov {}, rax", accumulator));
+            let next = self.compile_hinted(operand, "rax", &ty, scope)?;
+            self.emit(1, "mov rcx, rax");
+            self.emit(1, format!("mov rax, {}", accumulator));
+            ty = self.emit_step(name, operation, &ty, &next, operand.span)?;
+        }
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, rax", d));
+        }
+        Ok(ty)
+    }
+
+    // Evaluates expressions in order, the last one giving the value
+    fn compile_begin(name: &'static str) -> PrimitiveFunction {
+        let c = move |backend: &mut X86,
+                      args: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| match args.split_last() {
+            Some((last, effects)) => {
+                let tail = backend.tail.take();
+                backend.compile_effects(effects, scope)?;
+                backend.tail = tail;
+                backend.compile_expression(last, destination, scope)
+            }
+            None => Err(arity_error(name, "at least 1", 0, span).into()),
+        };
+        Rc::new(c)
+    }
+
+    // Evaluates expressions only for their side effects, ignoring their values
+    fn compile_effects(&mut self, args: &[Expression], scope: &mut Scope) -> CompileResult {
+        for arg in args {
+            self.compile_expression(arg, Some("rax"), scope)?;
+        }
+        Ok(())
+    }
+
+    // Compiles a body into rax, its last expression giving its value, which
+    // must be of type `expected` when given
+    fn compile_body(
+        &mut self,
+        body: &[Expression],
+        expected: Option<Type>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let (last, effects) = body.split_last().unwrap();
+        let tail = self.tail.take();
+        self.compile_effects(effects, scope)?;
+        self.tail = tail;
+        match expected {
+            Some(ty) => self.compile_typed(last, "rax", &ty, scope),
+            None => self.compile_expression(last, Some("rax"), scope),
+        }
+    }
+
+    // Binds names to values while evaluating a body, computing each value in
+    // the scope `kind` says. Every binding gets a register of its own, so an
+    // inner binding shadowing a name leaves the outer value alone.
+    fn compile_let(kind: LetKind) -> PrimitiveFunction {
+        let c = move |backend: &mut X86,
+                      args: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            let tail = backend.tail.take();
+            if kind == LetKind::Parallel {
+                if let Some(named_let) = split_named_let(args, span)? {
+                    return backend.compile_named_let(named_let, destination, scope);
+                }
+            }
+            let (bindings, body) = split_let(kind, args, span)?;
+            let mut inner = scope.clone();
+            let mut values = Vec::with_capacity(bindings.len());
+            for binding in &bindings {
+                let value_scope = if kind == LetKind::Parallel {
+                    &mut *scope
+                } else {
+                    &mut inner
+                };
+                let ty = match &binding.ty {
+                    Some(ty) => backend.compile_typed(binding.value, "rax", ty, value_scope)?,
+                    None => backend.compile_expression(binding.value, Some("rax"), value_scope)?,
+                };
+                let location = backend.virtual_register();
+                backend.emit(1, format!("mov {}, rax", location));
+                let variable = Variable {
+                    location,
+                    ty,
+                    assignable: true,
+                };
+                if kind == LetKind::Parallel {
+                    values.push((binding.name, variable));
+                } else {
+                    inner.insert(binding.name.to_string(), variable);
+                }
+            }
+            for (name, variable) in values {
+                inner.insert(name.to_string(), variable);
+            }
+            let (last, effects) = body.split_last().unwrap();
+            backend.compile_effects(effects, &mut inner)?;
+            backend.tail = tail;
+            backend.compile_expression(last, destination, &mut inner)
+        };
+        Rc::new(c)
+    }
+
+    // Loop whose bindings start with the values given and get those of each
+    // call to the loop in tail position of its body, which jumps back to
+    // its start. The value of the body when it doesn't call the loop is
+    // that of the loop.
+    fn compile_named_let(
+        &mut self,
+        (name, result, bindings, body): NamedLet,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let mut inner = scope.clone();
+        let mut variables = Vec::with_capacity(bindings.len());
+        for binding in &bindings {
+            let ty = match &binding.ty {
+                Some(ty) => self.compile_typed(binding.value, "rax", ty, scope)?,
+                None => self.compile_expression(binding.value, Some("rax"), scope)?,
+            };
+            let location = self.virtual_register();
+            self.emit(1, format!("mov {}, rax", location));
+            variables.push(Variable {
+                location,
+                ty,
+                assignable: false,
+            });
+        }
+        for (binding, variable) in bindings.iter().zip(&variables) {
+            inner.insert(binding.name.to_string(), variable.clone());
+        }
+
+        let head = self.label("loop");
+        self.emit(0, format!("{}:", head));
+        self.loops.push(Loop {
+            name: name.to_string(),
+            head,
+            bindings: variables,
+            result: result.clone(),
+        });
+        let (last, effects) = body.split_last().unwrap();
+        let compiled = self.compile_effects(effects, &mut inner).and_then(|_| {
+            self.tail = Some(Tail::Loop(name.to_string()));
+            self.compile_typed(last, "rax", &result, &mut inner)
+        });
+        self.tail = None;
+        self.loops.pop();
+        compiled?;
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, rax", d));
+        }
+        Ok(result)
+    }
+
+    // Call to the loop `self.loops[index]` in tail position of its body. The
+    // new values are all computed before any binding changes.
+    fn compile_loop_jump(
+        &mut self,
+        index: usize,
+        args: &[Expression],
+        span: Span,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let bindings = self.loops[index].bindings.clone();
+        if args.len() != bindings.len() {
+            let expected = bindings.len().to_string();
+            return Err(arity_error(&self.loops[index].name, &expected, args.len(), span).into());
+        }
+        let mut values = Vec::with_capacity(args.len());
+        for (arg, binding) in args.iter().zip(&bindings) {
+            self.compile_typed(arg, "rax", &binding.ty, scope)?;
+            let value = self.virtual_register();
+            self.emit(1, format!("mov {}, rax", value));
+            values.push(value);
+        }
+        for (binding, value) in bindings.iter().zip(values) {
+            self.emit(1, format!("mov {}, {}", binding.location, value));
+        }
+        let jump = format!("jmp {}", self.loops[index].head);
+        self.emit(1, jump);
+        Ok(self.loops[index].result.clone())
+    }
+
+    // Global variable or constant of the module, a quadword in the data or
+    // constant data section
+    fn compile_global(
+        &mut self,
+        kind: &str,
+        args: &[Expression],
+        span: Span,
+        scope: &mut Scope,
+    ) -> CompileResult {
+        let global = split_global(kind, args, span, &self.constants)?;
+        if scope.contains_key(global.name) || self.signatures.contains_key(global.name) {
+            return Err(defined_twice(global.name, args[0].span).into());
+        }
+        let value = match &global.value {
+            Value::Int(int, _) => (*int as i64).to_string(),
+            Value::Float(float) => format!("0x{:016X}", float.to_bits()),
+            Value::Bool(boolean) => (*boolean as u8).to_string(),
+            Value::Str(string) => self.string_constant(string),
+        };
+        let line = format!("{}: dq {}", global_label(global.name), value);
+        if global.constant {
+            // The address of a string can't go in .rodata, where the dynamic
+            // linker of a PIE binary would have to relocate it
+            if let Value::Str(_) = global.value {
+                self.relro.push(line);
+            } else {
+                self.rodata.push(line);
+            }
+            self.constants.insert(global.name.to_string(), global.value);
+        } else {
+            self.data.push(line);
+        }
+        scope.insert(
+            global.name.to_string(),
+            Variable {
+                location: global_location(global.name),
+                ty: global.ty,
+                assignable: !global.constant,
+            },
+        );
+        Ok(())
+    }
+
+    // Moves a new value to where a variable lives, evaluating to it
+    fn compile_set(
+        &mut self,
+        args: &[Expression],
+        span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let (name, value) = split_set(args, span)?;
+        let variable = match scope.get(name) {
+            Some(variable) if variable.assignable => variable.clone(),
+            Some(variable) if variable.location == global_location(name) => {
+                return Err(cannot_assign(name, "a constant", args[0].span).into())
+            }
+            Some(_) => return Err(cannot_assign(name, UNASSIGNABLE, args[0].span).into()),
+            None if self.signatures.contains_key(name) => {
+                return Err(cannot_assign(name, "a function", args[0].span).into())
+            }
+            None => {
+                return Err(Diagnostic::error(
+                    "E0101",
+                    format!("attempt to assign undefined variable `{}`", name),
+                )
+                .with_span(args[0].span)
+                .with_label("not found in this scope")
+                .with_suggestion(name, scope.keys().map(String::as_str))
+                .into())
+            }
+        };
+        self.compile_typed(value, "rax", &variable.ty, scope)?;
+        self.emit(1, format!("mov {}, rax", variable.location));
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, rax", d));
+        }
+        Ok(variable.ty)
+    }
+
+    // Evaluates the body as long as the test is true, evaluating to 0
+    fn compile_while(
+        &mut self,
+        args: &[Expression],
+        span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let (test, body) = args
+            .split_first()
+            .ok_or_else(|| arity_error("while", "at least 1", 0, span))?;
+        let head = self.label("while");
+        let end_label = self.label("endwhile");
+        self.emit(0, format!("{}:", head));
+        let ty = self.compile_expression(test, Some("rax"), scope)?;
+        expect_type(&Type::Bool, &ty, test.span)?;
+        self.emit(1, "test rax, rax");
+        self.emit(1, format!("jz {}", end_label));
+        self.compile_effects(body, scope)?;
+        self.emit(1, format!("jmp {}", head));
+
+        self.emit(0, format!("{}:", end_label));
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, 0", d));
+        }
+        Ok(Type::DEFAULT)
+    }
+
+    // Evaluates the body with a counter bound to each integer from 0 up to
+    // the count, excluded, evaluating to 0
+    fn compile_dotimes(
+        &mut self,
+        args: &[Expression],
+        span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let (counter, body) = split_dotimes(args, span)?;
+        let ty = match counter.ty {
+            Some(ty) => self.compile_typed(counter.value, "rax", &ty, scope)?,
+            None => self.compile_expression(counter.value, Some("rax"), scope)?,
+        };
+        let int = match ty {
+            Type::Int(int) => int,
+            _ => return Err(expected_integer(&ty, counter.value.span).into()),
+        };
+        let count = self.virtual_register();
+        self.emit(1, format!("mov {}, rax", count));
+        let index = self.virtual_register();
+        self.emit(1, format!("mov {}, 0", index));
+
+        let head = self.label("dotimes");
+        let end_label = self.label("enddotimes");
+        self.emit(0, format!("{}:", head));
+        self.emit(1, format!("mov rax, {}", index));
+        self.emit(1, format!("cmp rax, {}", count));
+        let at_least = if int.is_signed() { "jge" } else { "jae" };
+        self.emit(1, format!("{} {}", at_least, end_label));
+        let mut inner = scope.clone();
+        inner.insert(
+            counter.name.to_string(),
+            Variable {
+                location: index.clone(),
+                ty,
+                assignable: false,
+            },
+        );
+        self.compile_effects(body, &mut inner)?;
+        self.emit(1, format!("mov rax, {}", index));
+        self.emit(1, "add rax, 1");
+        self.emit(1, format!("mov {}, rax", index));
+        self.emit(1, format!("jmp {}", head));
+
+        self.emit(0, format!("{}:", end_label));
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, 0", d));
+        }
+        Ok(Type::DEFAULT)
+    }
+
+    // Moves the arguments passed in registers there
+    fn emit_argument_moves(
+        &mut self,
+        registers: &[Option<&'static str>],
+        values: &[String],
+        types: &[Type],
+    ) {
+        for (i, register) in registers.iter().enumerate() {
+            if let Some(register) = register {
+                let mov = if types[i] == Type::Float {
+                    "movq"
+                } else {
+                    "mov"
+                };
+                self.emit(1, format!("{} {}, {}", mov, register, values[i]));
+            }
+        }
+    }
+
+    // Calls a C library function with the 16 byte stack alignment it expects.
+    // `vector_args` is the number of xmm registers holding variadic arguments.
+    fn emit_libc_call(&mut self, function: &str, vector_args: usize) {
+        let padding = self.depth % 2 == 1;
+        if padding {
+            self.emit(1, "sub rsp, 8");
+        }
+        if vector_args == 0 {
+            self.emit(1, "xor eax, eax");
+        } else {
+            self.emit(1, format!("mov eax, {}", vector_args));
+        }
+        self.emit(1, format!("call {} wrt ..plt", function));
+        if padding {
+            self.emit(1, "add rsp, 8");
+        }
+    }
+
+    // Pushes a 64 bit `operand` on the stack
+    fn emit_push(&mut self, operand: &str) {
+        self.emit(1, format!("push {}", operand));
+        self.depth += 1;
+    }
+
+    // Pops the 64 bit value on top of the stack into `register`
+    fn emit_pop(&mut self, register: &str) {
+        self.emit(1, format!("pop {}", register));
+        self.depth -= 1;
+    }
+
+    // Removes the top `words` 64 bit values from the stack
+    fn emit_drop(&mut self, words: usize) {
+        if words > 0 {
+            self.emit(1, format!("add rsp, {}", 8 * words));
+            self.depth -= words;
+        }
+    }
+
+    // New virtual register in the function being compiled, given a physical
+    // register or a stack slot once the function is compiled
+    fn virtual_register(&mut self) -> String {
+        self.registers += 1;
+        allocator::virtual_register(self.registers)
+    }
+
+    // Operation described by `operation`, folded left to right over its
+    // operands, or applied to each adjacent pair of them for comparisons. A
+    // `-` with a single operand negates it. Floats are kept as their bit
+    // pattern in general purpose registers and only moved into xmm registers
+    // to operate on them.
+    fn compile_operation(name: &'static str, operation: Operation) -> PrimitiveFunction {
+        let c = move |backend: &mut X86,
+                      args: &[Expression],
+                      span: Span,
+                      destination: Option<&str>,
+                      scope: &mut Scope| {
+            if name == "-" && args.len() == 1 {
+                return backend.compile_negation(&args[0], destination, scope);
+            }
+            let operands = operands(name, operation.arity, args, span)?;
+            let values = backend.compile_operands(&operands, scope)?;
+
+            let ty = if let Arity::Chain = operation.arity {
+                // Every adjacent pair must compare true, r8 holds the
+                // comparisons so far
+                if values.len() == 1 {
+                    let (_, ty) = &values[0];
+                    operand_type(name, &operation, ty, ty, operands[0].span)?;
+                    backend.emit(1, "mov rax, 1");
+                }
+                for (i, pair) in values.windows(2).enumerate() {
+                    let ((left, ty1), (right, ty2)) = (&pair[0], &pair[1]);
+                    backend.emit_operand_load("rax", left);
+                    backend.emit_operand_load("rcx", right);
+                    let span = operands[i + 1].span;
+                    backend.emit_step(name, &operation, ty1, ty2, span)?;
+                    if i > 0 {
+                        backend.emit(1, "and rax, r8");
+                    }
+                    if i + 2 < values.len() {
+                        backend.emit(1, "mov r8, rax");
+                    }
+                }
+                Type::Bool
+            } else {
+                // The accumulated value is kept in rax, the next operand in rcx
+                let (first, ty) = &values[0];
+                let mut ty = ty.clone();
+                backend.emit_operand_load("rax", first);
+                if values.len() == 1 {
+                    operand_type(name, &operation, &ty, &ty, operands[0].span)?;
+                }
+                for ((operand, next), expression) in values.iter().zip(&operands).skip(1) {
+                    backend.emit_operand_load("rcx", operand);
+                    ty = backend.emit_step(name, &operation, &ty, next, expression.span)?;
+                }
+                ty
+            };
+
+            if let Some(d) = destination {
+                backend.emit(1, format!("mov {}, rax", d));
+            }
+            Ok(ty)
+        };
+        Rc::new(c)
+    }
+
+    // Negates a number, flipping the sign bit of floats so that the negation
+    // of zero is minus zero
+    fn compile_negation(
+        &mut self,
+        expression: &Expression,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let ty = self.compile_expression(expression, Some("rax"), scope)?;
+        expect_number(&ty, expression.span)?;
+        match ty {
+            Type::Int(int) => {
+                self.emit(1, "neg rax");
+                self.emit_wrap(int);
+            }
+            _ => self.emit(1, "btc rax, 63"),
+        }
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, rax", d));
+        }
+        Ok(ty)
+    }
+
+    // Applies a binary operation to rax, of type `ty1`, and rcx, of type
+    // `ty2`, leaving the result in rax and returning its type. An integer
+    // operand is promoted to float when the other one is a float.
+    fn emit_step(
+        &mut self,
+        name: &str,
+        operation: &Operation,
+        ty1: &Type,
+        ty2: &Type,
+        span: Span,
+    ) -> CompileResult<Type> {
+        let ty = operand_type(name, operation, ty1, ty2, span)?;
+        if ty == Type::Float {
+            self.emit_float_load("xmm0", "rax", ty1);
+            self.emit_float_load("xmm1", "rcx", ty2);
+        }
+        let unsigned = matches!(ty, Type::Int(int) if !int.is_signed());
+        let instruction = if unsigned {
+            operation.unsigned
+        } else {
+            operation.signed
+        };
+        match (operation.kind, &ty) {
+            (OperationKind::Comparison, Type::Float) => {
+                self.emit_float_comparison(operation.float.unwrap());
+                return Ok(Type::Bool);
+            }
+            (OperationKind::Comparison, _) => {
+                self.emit(1, "cmp rax, rcx");
+                self.emit(1, format!("set{} al", instruction));
+                self.emit(1, "movzx eax, al");
+                return Ok(Type::Bool);
+            }
+            (OperationKind::Remainder | OperationKind::Modulo, Type::Float) => {
+                // There's no instruction for it. The divisor is kept on the
+                // stack across the call when the result is adjusted with it.
+                let modulo = operation.kind == OperationKind::Modulo;
+                if modulo {
+                    self.emit(1, "movq rcx, xmm1");
+                    self.emit_push("rcx");
+                }
+                self.emit_libc_call("fmod", 0);
+                self.emit(1, "movq rax, xmm0");
+                if modulo {
+                    self.emit_pop("rcx");
+                    self.emit_modulo_adjustment(&Type::Float);
+                }
+            }
+            (_, Type::Float) => {
+                self.emit(1, format!("{} xmm0, xmm1", operation.float.unwrap()));
+                self.emit(1, "movq rax, xmm0");
+            }
+            (OperationKind::Division, _)
+            | (OperationKind::Remainder, _)
+            | (OperationKind::Modulo, _) => {
+                // The dividend is rdx:rax, the remainder is left in rdx
+                if unsigned {
+                    self.emit(1, "xor edx, edx");
+                } else {
+                    self.emit(1, "cqo");
+                }
+                self.emit(1, format!("{} rcx", instruction));
+                if operation.kind != OperationKind::Division {
+                    self.emit(1, "mov rax, rdx");
+                }
+                if operation.kind == OperationKind::Modulo && !unsigned {
+                    self.emit_modulo_adjustment(&ty);
+                }
+            }
+            (OperationKind::Shift, Type::Int(int)) => {
+                // Shift amounts are taken modulo the width of the operand, so
+                // narrow integers shift like they do in the llvm backend
+                self.emit(1, format!("and ecx, {}", int.bits() - 1));
+                self.emit(1, format!("{} rax, cl", instruction));
+            }
+            _ => self.emit(1, format!("{} rax, rcx", instruction)),
+        }
+        if let Type::Int(int) = ty {
+            self.emit_wrap(int);
+        }
+        Ok(ty)
+    }
+
+    // Moves a non zero remainder in rax with a sign different from the
+    // divisor's in rcx into the divisor's range, e.g. (mod -7 2) is 1 and
+    // not -1. Both hold the bit pattern of floats when `ty` is a float.
+    fn emit_modulo_adjustment(&mut self, ty: &Type) {
+        let done = self.label("modok");
+        if *ty == Type::Float {
+            // Without the sign bit, so minus zero is zero too
+            self.emit(1, "mov rdx, rax");
+            self.emit(1, "shl rdx, 1");
+        } else {
+            self.emit(1, "test rax, rax");
+        }
+        self.emit(1, format!("jz {}", done));
+        self.emit(1, "mov rdx, rax");
+        self.emit(1, "xor rdx, rcx");
+        self.emit(1, format!("jns {}", done));
+        if *ty == Type::Float {
+            self.emit(1, "movq xmm0, rax");
+            self.emit(1, "movq xmm1, rcx");
+            self.emit(1, "addsd xmm0, xmm1");
+            self.emit(1, "movq rax, xmm0");
+        } else {
+            self.emit(1, "add rax, rcx");
+        }
+        self.emit(0, format!("{}:", done));
+    }
+
+    // Sets rax to whether xmm0 compares to xmm1 as `condition` says. An
+    // unordered comparison, with a NaN operand, sets the carry, zero and
+    // parity flags, so it's only true for `ne`.
+    fn emit_float_comparison(&mut self, condition: &str) {
+        match condition {
+            "b" | "be" => {
+                // Swapped to use the conditions that are false when unordered
+                self.emit(1, "ucomisd xmm1, xmm0");
+                self.emit(1, format!("set{} al", condition.replace('b', "a")));
+            }
+            _ => {
+                self.emit(1, "ucomisd xmm0, xmm1");
+                self.emit(1, format!("set{} al", condition));
+            }
+        }
+        match condition {
+            "e" => {
+                self.emit(1, "setnp cl");
+                self.emit(1, "and al, cl");
+            }
+            "ne" => {
+                self.emit(1, "setp cl");
+                self.emit(1, "or al, cl");
+            }
+            _ => {}
+        }
+        self.emit(1, "movzx eax, al");
+    }
+
+    // Compiles the operands of an operation, each value into its own register.
+    // Untyped integer literals take the type of the first other operand and
+    // are kept as immediates; they have no side effects to reorder.
+    fn compile_operands(
+        &mut self,
+        operands: &[Expression],
+        scope: &mut Scope,
+    ) -> CompileResult<Vec<(Operand, Type)>> {
+        let mut values = Vec::with_capacity(operands.len());
+        let mut hint = None;
+        for operand in operands {
+            if untyped_integer(operand).is_none() {
+                let ty = self.compile_expression(operand, Some("rax"), scope)?;
+                let register = self.virtual_register();
+                self.emit(1, format!("mov {}, rax", register));
+                hint.get_or_insert(ty.clone());
+                values.push(Some((Operand::Register(register), ty)));
+            } else {
+                values.push(None);
+            }
+        }
+        let ty = match hint {
+            Some(Type::Int(int)) => int,
+            _ => IntType::I64,
+        };
+        let values = values.into_iter().zip(operands).map(|(value, operand)| {
+            match (value, untyped_integer(operand)) {
+                (Some(value), _) => Ok(value),
+                (None, Some(literal)) => {
+                    check_literal(literal, ty, operand.span)?;
+                    Ok((Operand::Immediate(literal), Type::Int(ty)))
+                }
+                (None, None) => unreachable!(),
+            }
+        });
+        values.collect()
+    }
+
+    // Moves an operand into `register`
+    fn emit_operand_load(&mut self, register: &str, operand: &Operand) {
+        match operand {
+            Operand::Register(value) => self.emit(1, format!("mov {}, {}", register, value)),
+            Operand::Immediate(value) => self.emit(1, format!("mov {}, {}", register, value)),
+        }
+    }
+
+    // Integers narrower than 64 bits are kept sign extended in registers, so
+    // a result in rax wraps around like it does in the llvm backend
+    fn emit_wrap(&mut self, int: IntType) {
+        match int {
+            IntType::I8 => self.emit(1, "movsx rax, al"),
+            IntType::I16 => self.emit(1, "movsx rax, ax"),
+            IntType::I32 => self.emit(1, "movsxd rax, eax"),
+            IntType::I64 | IntType::U64 => {}
+        }
+    }
+
+    // Loads a number held in `register` into an xmm register as a double
+    fn emit_float_load(&mut self, xmm: &str, register: &str, ty: &Type) {
+        match ty {
+            Type::Int(IntType::U64) => {
+                // cvtsi2sd is signed: values with the top bit set are halved,
+                // keeping the low bit for rounding, converted and doubled
+                self.emit(1, format!("cvtsi2sd {}, {}", xmm, register));
+                self.emit(1, format!("mov r10, {}", register));
+                self.emit(1, "shr r10, 1");
+                self.emit(1, format!("mov r11, {}", register));
+                self.emit(1, "and r11, 1");
+                self.emit(1, "or r10, r11");
+                self.emit(1, "cvtsi2sd xmm15, r10");
+                self.emit(1, "addsd xmm15, xmm15");
+                self.emit(1, "movq r10, xmm15");
+                self.emit(1, format!("movq r11, {}", xmm));
+                self.emit(1, format!("test {}, {}", register, register));
+                self.emit(1, "cmovs r11, r10");
+                self.emit(1, format!("movq {}, r11", xmm));
+            }
+            Type::Int(_) => self.emit(1, format!("cvtsi2sd {}, {}", xmm, register)),
+            _ => self.emit(1, format!("movq {}, {}", xmm, register)),
+        }
+    }
+
+    // Compiles `expression`, giving an untyped integer literal the type `hint`
+    // when that's an integer type
+    fn compile_hinted(
+        &mut self,
+        expression: &Expression,
+        destination: &str,
+        hint: &Type,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        match (untyped_integer(expression), hint) {
+            (Some(value), Type::Int(int)) => {
+                check_literal(value, *int, expression.span)?;
+                self.emit(1, format!("mov {}, {}", destination, value));
+                Ok(hint.clone())
+            }
+            _ => self.compile_expression(expression, Some(destination), scope),
+        }
+    }
+
+    // Compiles `expression` where a value of type `expected` is needed. Bools
+    // already are 0 or 1, so they can be used where an integer is expected.
+    fn compile_typed(
+        &mut self,
+        expression: &Expression,
+        destination: &str,
+        expected: &Type,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let ty = self.compile_hinted(expression, destination, expected, scope)?;
+        if !matches!((&ty, expected), (Type::Bool, Type::Int(_))) {
+            expect_type(expected, &ty, expression.span)?;
+        }
+        Ok(expected.clone())
+    }
+
+    fn run_assembler(&mut self, asmfile: &str, codefile: &str) -> CompileResult<String> {
+        let objfile = format!("{}.o", codefile);
+        run_tool(
+            Command::new("nasm")
+                .arg("-f")
+                .arg("elf64")
+                .arg("-o")
+                .arg(&objfile)
+                .arg(asmfile),
+        )?;
+        Ok(objfile)
+    }
+
+    fn run_linker(&mut self, objfile: &str, binary: &str) -> CompileResult {
+        // fmod, for the remainder of floats, is in libm
+        run_tool(
+            Command::new("gcc")
+                .arg("-o")
+                .arg(binary)
+                .arg(objfile)
+                .arg("-lm"),
+        )
+    }
+
+    fn write_asm(&mut self, output: &str, asm: String) -> CompileResult {
+        fs::File::create(output)
+            .and_then(|mut file| file.write_all(asm.as_bytes()))
+            .map_err(|error| {
+                Diagnostic::error("E0203", format!("failed to write `{}`", output))
+                    .with_note(error.to_string())
+                    .into()
+            })
+    }
+}
+
+impl Backend for X86 {
+    type S = Scope;
+
+    fn compile(&mut self, ast: &Expression) -> Result<String, Vec<Diagnostic>> {
+        self.emit_prefix();
+        let mut scope = Scope::new();
+        if let Err(error) = self.compile_expression(ast, None, &mut scope) {
+            self.errors.push(*error);
+        }
+        self.emit_postfix();
+
+        if self.errors.is_empty() {
+            Ok(self.output.borrow().to_string())
+        } else {
+            Err(self.errors.split_off(0))
+        }
+    }
+
+    fn build(&mut self, asm: String, input: &str, output: &str) -> CompileResult {
+        let asmfile = &format!("{}.asm", input);
+        self.write_asm(asmfile, asm)?;
+
+        let objfile = self.run_assembler(asmfile, input)?;
+        self.run_linker(&objfile, output)
+    }
+
+    fn compile_expression(
+        &mut self,
+        arg: &Expression,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let tail = self.tail.take();
+        let (origin, ty) = match &arg.kind {
+            ExpressionKind::List(items) if matches!(items.first(), Some(head) if matches!(head.kind, ExpressionKind::List(_))) =>
+            {
+                return Err(closures_unsupported(arg.span).into());
+            }
+            ExpressionKind::List(_vec) => {
+                let (function, args) = split_function(arg)?;
+                self.tail = tail;
+                return self.compile_call(&function, args, arg.span, destination, scope);
+            }
+            ExpressionKind::Symbol(symbol) => {
+                if let Some(variable) = scope.get(symbol) {
+                    (variable.location.to_string(), variable.ty.clone())
+                } else {
+                    self.errors
+                        .push(undefined_variable(symbol, arg.span, scope));
+                    return Ok(Type::DEFAULT);
+                }
+            }
+            ExpressionKind::Integer(int, ty) => {
+                (int.to_string(), Type::Int(ty.unwrap_or(IntType::I64)))
+            }
+            ExpressionKind::String(string) => {
+                let label = self.string_constant(string);
+                self.emit(1, format!("lea {}, [rel {}]", destination.unwrap(), label));
+                return Ok(Type::Str);
+            }
+            ExpressionKind::Float(float) => {
+                let bits = format!("0x{:016X}", float.to_bits());
+                self.emit(1, format!("mov {}, {}", destination.unwrap(), bits));
+                return Ok(Type::Float);
+            }
+            ExpressionKind::Boolean(boolean) => {
+                self.emit(
+                    1,
+                    format!("mov {}, {}", destination.unwrap(), *boolean as i32),
+                );
+                return Ok(Type::Bool);
+            }
+        };
+        self.emit(1, format!("mov {}, {}", destination.unwrap(), origin));
+        Ok(ty)
+    }
+
+    fn compile_call(
+        &mut self,
+        function: &str,
+        args: &[Expression],
+        span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let tail = self.tail.take();
+        if let Some(index) = self.loops.iter().rposition(|l| l.name == function) {
+            if tail != Some(Tail::Loop(function.to_string())) {
+                return Err(loop_outside_tail_position(function, span).into());
+            }
+            return self.compile_loop_jump(index, args, span, scope);
+        }
+        if let Some(fun) = self.primitive_functions.get(function).cloned() {
+            if TAIL_FORMS.contains(&function) {
+                self.tail = tail;
+            }
+            return fun(self, args, span, destination, scope);
+        }
+        if scope.get(function).is_some() {
+            return Err(closures_unsupported(span).into());
+        }
+
+        let signature = self.signatures.get(function).cloned();
+        if signature.is_none() {
+            self.errors.push(
+                Diagnostic::error(
+                    "E0102",
+                    format!("attempt to call undefined function `{}`", function),
+                )
+                .with_span(span)
+                .with_label("not found in this scope")
+                .with_suggestion(function, self.signatures.keys().map(String::as_str)),
+            );
+        }
+        if let Some(signature) = &signature {
+            if args.len() != signature.params.len() {
+                let expected = signature.params.len().to_string();
+                return Err(arity_error(function, &expected, args.len(), span).into());
+            }
+        }
+
+        // Arguments are evaluated left to right into registers of their own,
+        // then moved to where the ABI passes them
+        let mut values = Vec::with_capacity(args.len());
+        let mut types = Vec::with_capacity(args.len());
+        for (i, arg) in args.iter().enumerate() {
+            let ty = match &signature {
+                Some(signature) => self.compile_typed(arg, "rax", &signature.params[i], scope)?,
+                None => self.compile_expression(arg, Some("rax"), scope)?,
+            };
+            let register = self.virtual_register();
+            self.emit(1, format!("mov {}, rax", register));
+            values.push(register);
+            types.push(ty);
+        }
+        let registers = argument_registers(&types);
+        let result = signature
+            .as_ref()
+            .map_or(Type::DEFAULT, |signature| signature.result.clone());
+
+        // A call in tail position returning what the caller does, with every
+        // argument in a register, jumps to the function once the frame of
+        // the caller is gone. The function then returns to the caller of the
+        // caller.
+        let in_registers = registers.iter().all(Option::is_some);
+        if let Some(Tail::Function(caller)) = &tail {
+            if caller.result == result && in_registers && self.depth == 0 {
+                self.emit_argument_moves(&registers, &values, &types);
+                self.emit(1, EPILOGUE);
+                self.emit(1, format!("jmp {}", function_label(function)));
+                return Ok(result);
+            }
+        }
+
+        // The stack must be 16 byte aligned at the call, after the arguments
+        // passed on it are pushed, the first one last
+        let on_stack: Vec<usize> = (0..args.len())
+            .filter(|i| registers[*i].is_none())
+            .collect();
+        let mut pushed = 0;
+        if (self.depth + on_stack.len()) % 2 == 1 {
+            self.emit(1, "sub rsp, 8");
+            self.depth += 1;
+            pushed += 1;
+        }
+        for i in on_stack.iter().rev() {
+            self.emit_push(&values[*i]);
+            pushed += 1;
+        }
+        self.emit_argument_moves(&registers, &values, &types);
+
+        self.emit(1, format!("call {}", function_label(function)));
+        self.emit_drop(pushed);
+
+        if result == Type::Float {
+            self.emit(1, "movq rax, xmm0");
+        }
+        if let Some(d) = destination {
+            self.emit(1, format!("mov {}, rax", d));
+        }
+        Ok(result)
+    }
+
+    fn compile_define(
+        &mut self,
+        args: &[Expression],
+        span: Span,
+        _destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        let (name, result, params, body) = split_def_expression(args, span)?;
+        let signature = Signature {
+            params: params.iter().map(|(_, ty)| ty.clone()).collect(),
+            result: result.clone(),
+        };
+        self.signatures.insert(name.clone(), signature.clone());
+
+        // Params passed in registers are moved to registers of their own,
+        // those passed on the stack are above the return address
+        let label = function_label(&name);
+        self.emit(1, format!("global {}", label));
+        self.emit(0, format!("{}:", label));
+        self.emit(1, "push rbp");
+        self.emit(1, "mov rbp, rsp");
+        let body_position = self.output.borrow().len();
+        self.registers = 0;
+        self.depth = 0;
+
+        let mut child_scope = scope.clone();
+        let types: Vec<Type> = params.iter().map(|(_, ty)| ty.clone()).collect();
+        let mut stack_params = 0;
+        for ((name, ty), register) in params.iter().zip(argument_registers(&types)) {
+            let location = match register {
+                Some(register) => {
+                    let location = self.virtual_register();
+                    let mov = if *ty == Type::Float { "movq" } else { "mov" };
+                    self.emit(1, format!("{} {}, {}", mov, location, register));
+                    location
+                }
+                None => {
+                    stack_params += 1;
+                    format!("qword [rbp + {}]", 8 + 8 * stack_params)
+                }
+            };
+            child_scope.insert(
+                name.to_string(),
+                Variable {
+                    location,
+                    ty: ty.clone(),
+                    assignable: true,
+                },
+            );
+        }
+
+        let (last, effects) = body.split_last().unwrap();
+        self.compile_effects(effects, &mut child_scope)?;
+        self.tail = Some(Tail::Function(signature));
+        let compiled = self.compile_typed(last, "rax", &result, &mut child_scope);
+        // Left over when the last expression was a literal
+        self.tail = None;
+        compiled?;
+        if result == Type::Float {
+            self.emit(1, "movq xmm0, rax");
+        }
+
+        // With the whole body known its virtual registers are allocated. The
+        // frame holds the spilled ones and the callee saved registers used,
+        // and is kept a multiple of 16 bytes so the stack stays aligned.
+        let body = self.output.borrow_mut().split_off(body_position);
+        let lines: Vec<String> = body.lines().map(str::to_string).collect();
+        let allocation = allocator::allocate(&lines);
+        let slots = allocation.spilled + allocation.saved.len();
+        if slots > 0 {
+            self.emit(1, format!("sub rsp, {}", 16 * slots.div_ceil(2)));
+        }
+        let saved: Vec<(&str, usize)> = (allocation.spilled + 1..)
+            .zip(&allocation.saved)
+            .map(|(slot, register)| (*register, 8 * slot))
+            .collect();
+        for (register, offset) in &saved {
+            self.emit(1, format!("mov qword [rbp - {}], {}", offset, register));
+        }
+        let epilogue = |backend: &mut X86| {
+            for (register, offset) in &saved {
+                backend.emit(1, format!("mov {}, qword [rbp - {}]", register, offset));
+            }
+            backend.emit(1, "leave");
+        };
+        for line in allocation.code {
+            if line.trim() == EPILOGUE {
+                epilogue(self);
+            } else {
+                self.emit(0, line);
+            }
+        }
+        epilogue(self);
+        self.emit(1, "ret\n");
+        Ok(Type::DEFAULT)
+    }
+
+    fn compile_module(
+        &mut self,
+        args: &[Expression],
+        _span: Span,
+        destination: Option<&str>,
+        scope: &mut Scope,
+    ) -> CompileResult<Type> {
+        // Functions can be called before the form defining them
+        for expression in args {
+            if let Some((name, signature)) = declared_function(expression) {
+                self.signatures.insert(name, signature);
+            }
+        }
+        // So can globals, whose values are known at compile time
+        for expression in args {
+            if let (Some(kind), ExpressionKind::List(items)) =
+                (definition_kind(expression), &expression.kind)
+            {
+                if kind != "def" {
+                    if let Err(error) =
+                        self.compile_global(kind, &items[1..], expression.span, scope)
+                    {
+                        self.errors.push(*error);
+                    }
+                }
+            }
+        }
+
+        // A broken form doesn't stop the rest of the module from being checked
+        for expression in args {
+            let compiled = match definition_kind(expression) {
+                Some("def") => self
+                    .compile_expression(expression, Some("rax"), scope)
+                    .map(|_| ()),
+                Some(_) => Ok(()),
+                None => Err(not_a_definition(expression.span).into()),
+            };
+            if let Err(error) = compiled {
+                self.errors.push(*error);
+            }
+        }
+        if let Some(dest) = destination {
+            if dest == "rax" {
+            } else {
+                self.emit(1, format!("mov {}, rax", dest));
+            }
+        }
+        Ok(Type::DEFAULT)
+    }
+
+    fn emit<T>(&mut self, depth: usize, code: T)
+    where
+        T: Into<String>,
+    {
+        let mut indent = String::with_capacity(depth);
+        for _ in 0..depth {
+            indent.push('\t');
+        }
+
+        self.output
+            .borrow_mut()
+            .push_str(&format!("{}{}\n", indent, code.into()));
+    }
+}
+
+pub(crate) fn new() -> Box<dyn Backend<S = Scope>> {
+    Box::new(X86::new())
+}
+
+fn undefined_variable(symbol: &str, span: Span, scope: &Scope) -> Diagnostic {
+    Diagnostic::error(
+        "E0101",
+        format!("attempt to reference undefined variable `{}`", symbol),
+    )
+    .with_span(span)
+    .with_label("not found in this scope")
+    .with_suggestion(symbol, scope.keys().map(String::as_str))
+}
+
+fn param_not_symbol(span: Span) -> Diagnostic {
+    Diagnostic::error("E0104", "function param must be a symbol")
+        .with_span(span)
+        .with_label("expected a symbol")
+}
+
+fn split_function(list: &Expression) -> CompileResult<(String, &[Expression])> {
+    if let ExpressionKind::List(vec) = &list.kind {
+        match vec.first() {
+            Some(Expression {
+                kind: ExpressionKind::Symbol(name),
+                ..
+            }) => Ok((name.to_owned(), vec.split_at(1).1)),
+            Some(head) => Err(
+                Diagnostic::error("E0105", "first list item is not a symbol")
+                    .with_span(head.span)
+                    .with_label("expected a function name")
+                    .into(),
+            ),
+            None => Err(Diagnostic::error("E0105", "cannot evaluate an empty list")
+                .with_span(list.span)
+                .with_label("expected a function call")
+                .into()),
+        }
+    } else {
+        Err(Diagnostic::error("E0105", "expression is not a list item")
+            .with_span(list.span)
+            .into())
+    }
+}
+
+// Label of the code for a function, `main` is the C entry point so the
+// program's own main function gets another name
+fn function_label(name: &str) -> String {
+    if name == "main" {
+        "program_main".to_string()
+    } else {
+        name.replace("-", "_")
+    }
+}
+
+// Label of the quadword holding the global `name`
+fn global_label(name: &str) -> String {
+    format!("{}.global", function_label(name))
+}
+
+fn global_location(name: &str) -> String {
+    format!("qword [rel {}]", global_label(name))
+}
+
+// Register passing each argument of a call with arguments of the given
+// types, or None for those passed on the stack
+fn argument_registers(types: &[Type]) -> Vec<Option<&'static str>> {
+    let mut integers = PARAM_REGISTERS.iter();
+    let mut floats = FLOAT_PARAM_REGISTERS.iter();
+    types
+        .iter()
+        .map(|ty| match ty {
+            Type::Float => floats.next().copied(),
+            _ => integers.next().copied(),
+        })
+        .collect()
+}
+
+// Type both operands of `operation` are converted to before applying it
+fn operand_type(
+    name: &str,
+    operation: &Operation,
+    ty1: &Type,
+    ty2: &Type,
+    span: Span,
+) -> CompileResult<Type> {
+    let ty = if operation.float.is_some() && (*ty1 == Type::Float || *ty2 == Type::Float) {
+        expect_number(ty1, span)?;
+        expect_number(ty2, span)?;
+        promoted_type(ty1, ty2, span)?
+    } else {
+        expect_type(ty1, ty2, span)?;
+        ty1.clone()
+    };
+    match ty {
+        Type::Int(_) => Ok(ty),
+        Type::Float if operation.float.is_some() => Ok(ty),
+        Type::Bool if operation.boolean => Ok(ty),
+        _ => Err(Diagnostic::error("E0109", "mismatched types")
+            .with_span(span)
+            .with_label(format!("`{}` cannot be applied to `{}`", name, ty))
+            .into()),
+    }
+}
+
+// Splits a def form into its name, return type, typed params and the
+// expressions of its body
+fn split_def_expression(args: &[Expression], span: Span) -> CompileResult<Definition<'_>> {
+    if args.len() < 3 {
+        return Err(arity_error("def", "at least 3", args.len(), span).into());
+    }
+    let (name, result) = if let ExpressionKind::Symbol(name) = &args[0].kind {
+        split_annotation(name, args[0].span)?
+    } else {
+        return Err(malformed_def("first item must be a symbol", args[0].span).into());
+    };
+    let params = if let ExpressionKind::List(vec) = &args[1].kind {
+        vec.iter()
+            .map(|param| {
+                if let ExpressionKind::Symbol(param_name) = &param.kind {
+                    let (param_name, ty) = split_annotation(param_name, param.span)?;
+                    Ok((param_name.to_string(), ty))
+                } else {
+                    Err(param_not_symbol(param.span).into())
+                }
+            })
+            .collect::<CompileResult<Vec<Param>>>()?
+    } else {
+        return Err(malformed_def("second item must be a list", args[1].span).into());
+    };
+    Ok((name.to_string(), result, params, &args[2..]))
+}
+
+fn malformed_def(message: &str, span: Span) -> Diagnostic {
+    Diagnostic::error("E0103", format!("{} in def statement", message)).with_span(span)
+}
+
+fn closures_unsupported(span: Span) -> Diagnostic {
+    Diagnostic::error("E0114", "closures are not supported by the x86 backend")
+        .with_span(span)
+        .with_label("only functions defined with def can be called")
+        .with_help("use the llvm backend")
+}
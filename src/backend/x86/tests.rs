@@ -0,0 +1,320 @@
+use super::*;
+use crate::parser::parse;
+
+fn compile(code: &str) -> String {
+    X86::new().compile(&parse(code).unwrap()).unwrap()
+}
+
+#[test]
+fn calls_in_tail_position_are_jumps() {
+    let asm = compile(include_str!("../../../examples/countdown.ulisp"));
+    assert!(asm.contains("\tleave\n\tjmp count_down\n"));
+    assert!(!asm.contains("call count_down"));
+
+    let asm = compile(include_str!("../../../examples/fibonacci.ulisp"));
+    assert!(asm.contains("\tcall fib\n"));
+}
+
+#[test]
+fn variadic_operations_fold_their_operands() {
+    // No operands is the identity
+    let asm = compile("(def f () (+))");
+    assert!(asm.contains("f:\n\tpush rbp\n\tmov rbp, rsp\n\tmov rax, 0\n\tleave\n"));
+
+    // Each operand is added to the sum of the ones before it
+    let asm = compile("(def f (a b c) (+ a b c))");
+    assert_eq!(asm.matches("\tadd rax, rcx\n").count(), 2);
+
+    // The sum becomes a float once a float is added to it
+    let asm = compile("(def f:f64 (a b:f64) (+ a b 1))");
+    assert!(asm.contains("\tcvtsi2sd xmm0, rax\n\tmovq xmm1, rcx\n\taddsd xmm0, xmm1\n"));
+    assert!(asm.contains("\tcvtsi2sd xmm1, rcx\n\taddsd xmm0, xmm1\n"));
+
+    // A single operand of `-` is negated
+    let asm = compile("(def f (x) (- x))");
+    assert!(asm.contains("\tneg rax\n"));
+    assert!(!asm.contains("\tsub rax, rcx\n"));
+
+    // Chained comparisons compare each adjacent pair, r8 holding the first
+    let asm = compile("(def f:bool (a b c) (< a b c))");
+    assert_eq!(asm.matches("\tsetl al\n").count(), 2);
+    assert!(asm.contains("\tand rax, r8\n"));
+}
+
+#[test]
+fn operations_without_operands_need_an_identity() {
+    let errors = X86::new()
+        .compile(&parse("(def f () (/))").unwrap())
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "E0106");
+    assert_eq!(errors[0].message, "`/` takes at least 1 argument, found 0");
+}
+
+#[test]
+fn calls_to_undefined_functions_are_reported() {
+    let code = "(def plus-two (x) (+ x 2)) (def main () (plus-tw 1))";
+    let errors = X86::new().compile(&parse(code).unwrap()).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].code, "E0102");
+    assert_eq!(errors[0].span.map(|span| span.start), code.find("(plus-tw"));
+    assert_eq!(errors[0].help.as_deref(), Some("did you mean `plus-two`?"));
+}
+
+#[test]
+fn fibonacci_compiles_to_comparisons_and_branches() {
+    let asm = compile(include_str!("../../../examples/fibonacci.ulisp"));
+    assert!(asm.contains("\tcmp rax, rcx\n\tsetl al\n\tmovzx eax, al\n"));
+    assert!(asm.contains("\ttest rax, rax\n\tjz .Lelse"));
+    assert!(asm.contains("\tsub rax, rcx\n"));
+    assert!(asm.contains("\tadd rax, rcx\n"));
+    assert!(asm.contains("\tcall fib\n"));
+}
+
+#[test]
+fn remainders_and_shifts_match_the_llvm_backend() {
+    // The remainder is left in rdx, and mod moves it into the range of the
+    // divisor when their signs differ
+    let asm = compile("(def f (a b) (rem a b))");
+    assert!(asm.contains("\tcqo\n\tidiv rcx\n\tmov rax, rdx\n"));
+    assert!(!asm.contains("xor rdx, rcx"));
+    let asm = compile("(def f (a b) (mod a b))");
+    assert!(asm.contains("\tmov rax, rdx\n\ttest rax, rax\n\tjz .Lmodok1\n"));
+    assert!(asm.contains("\txor rdx, rcx\n\tjns .Lmodok1\n\tadd rax, rcx\n.Lmodok1:\n"));
+    let asm = compile("(def f:f64 (a:f64 b:f64) (rem a b))");
+    assert!(asm.contains("\tcall fmod wrt ..plt\n"));
+
+    // Shift amounts are masked to the width of the operand
+    let asm = compile("(def f:i32 (a:i32 b:i32) (shl a b))");
+    assert!(asm.contains("\tand ecx, 31\n\tshl rax, cl\n\tmovsxd rax, eax\n"));
+    let asm = compile("(def f:u64 (a:u64 b:u64) (shr a b))");
+    assert!(asm.contains("\tand ecx, 63\n\tshr rax, cl\n"));
+}
+
+#[test]
+fn integer_operations_reject_floats() {
+    let errors = X86::new()
+        .compile(&parse("(def f:f64 (a:f64) (shl a a))").unwrap())
+        .unwrap_err();
+    assert_eq!(
+        errors[0].label.as_deref(),
+        Some("`shl` cannot be applied to `f64`")
+    );
+}
+
+// Builds a program printing `expression` and runs it, returning what it
+// prints, or None when nasm isn't installed to assemble it
+fn evaluate(expression: &str) -> Option<String> {
+    Command::new("nasm").arg("-v").output().ok()?;
+    let mut backend = X86::new();
+    let code = format!("(def main () (print {}))", expression);
+    let asm = backend.compile(&parse(&code).unwrap()).unwrap();
+    let input = std::env::temp_dir().join(format!("ulisp-x86-{}", std::process::id()));
+    let input = input.to_str().unwrap();
+    let binary = format!("{}.out", input);
+    backend.build(asm, input, &binary).unwrap();
+    let output = Command::new(&binary).output().unwrap();
+    for extension in &["asm", "o", "out"] {
+        let _ = fs::remove_file(format!("{}.{}", input, extension));
+    }
+    Some(String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn remainders_and_shifts_evaluate_like_the_llvm_backend() {
+    let cases = [
+        // mod takes the sign of the divisor, rem the sign of the dividend
+        ("(mod -7 2)", "1"),
+        ("(rem -7 2)", "-1"),
+        ("(mod 7 -2)", "-1"),
+        ("(mod -6 2)", "0"),
+        ("(mod -7.5 2.0)", "0.5"),
+        ("(rem -7.5 2.0)", "-1.5"),
+        ("(mod -7.5 2)", "0.5"),
+        // Shift amounts are masked to the width of the operand
+        ("(shl 1 65)", "2"),
+        ("(shl 1i32 33)", "2"),
+        ("(shl 1i8 7)", "-128"),
+        ("(shr -8 1)", "-4"),
+        ("(shr 0xffffffffffffffffu64 60)", "15"),
+        ("(- 5)", "-5"),
+        ("(- -128i8)", "-128"),
+        ("(xor 6 3)", "5"),
+    ];
+    for (expression, expected) in cases {
+        let Some(output) = evaluate(expression) else {
+            return;
+        };
+        assert_eq!(output, format!("{}\n", expected), "{}", expression);
+    }
+}
+
+const SUM7: &str = "(def sum7 (a b c d e f g) (+ a b c d e f g))";
+
+#[test]
+fn arguments_after_the_sixth_integer_are_passed_on_the_stack() {
+    let asm = compile(&format!(
+        "(module {} (def main () (sum7 1 2 3 4 5 6 7)))",
+        SUM7
+    ));
+    // The callee reads the seventh above its return address
+    assert!(asm.contains("\tmov rax, qword [rbp + 16]\n"));
+
+    // The caller pushes it after padding the stack to 16 bytes, and drops
+    // both once the call returns
+    let call = &asm[asm.find("program_main:").unwrap()..];
+    let pushes: Vec<&str> = call
+        .lines()
+        .filter(|line| line.starts_with("\tpush ") && *line != "\tpush rbp")
+        .collect();
+    assert_eq!(pushes.len(), 1);
+    assert!(call.contains(&format!("\tsub rsp, 8\n{}\n\tmov rdi, ", pushes[0])));
+    for register in &["rdi", "rsi", "rdx", "rcx", "r8", "r9"] {
+        assert!(call.contains(&format!("\tmov {}, ", register)));
+    }
+    assert!(call.contains("\tcall sum7\n\tadd rsp, 16\n"));
+
+    // An even number of them needs no padding
+    let asm = compile(
+        "(module
+           (def sum8 (a b c d e f g h) (+ a b c d e f g h))
+           (def main () (sum8 1 2 3 4 5 6 7 8)))",
+    );
+    let call = &asm[asm.find("program_main:").unwrap()..];
+    assert!(!call.contains("\tsub rsp, 8\n"));
+    assert!(call.contains("\tcall sum8\n\tadd rsp, 16\n"));
+}
+
+#[test]
+fn integer_and_float_arguments_use_registers_of_their_own() {
+    let asm = compile(
+        "(module
+           (def mixed:f64 (a x:f64 b y:f64) (+ a x b y))
+           (def main () (mixed 1 2.0 3 4.0) 0))",
+    );
+    let callee = &asm[asm.find("mixed:").unwrap()..asm.find("program_main:").unwrap()];
+    // Params are counted separately for each kind of register
+    assert!(callee.contains("\tmov rbx, rdi\n\tmovq r12, xmm0\n\tmov r13, rsi\n\tmovq r14, xmm1\n"));
+    // The result is returned in xmm0
+    assert!(callee.contains("\tmovq xmm0, rax\n\tmov rbx, "));
+
+    let caller = &asm[asm.find("program_main:").unwrap()..];
+    assert!(caller.contains("\tmov rdi, "));
+    assert!(caller.contains("\tmovq xmm0, "));
+    assert!(caller.contains("\tmov rsi, "));
+    assert!(caller.contains("\tmovq xmm1, "));
+    assert!(caller.contains("\tcall mixed\n\tmovq rax, xmm0\n"));
+
+    // The ninth float goes on the stack, whatever the integers do
+    let asm = compile(
+        "(module
+           (def f:f64 (a:f64 b:f64 c:f64 d:f64 e:f64 f:f64 g:f64 h:f64 i:f64) i)
+           (def main () (f 1.0 2.0 3.0 4.0 5.0 6.0 7.0 8.0 9.0) 0))",
+    );
+    assert!(asm.contains("\tmov rax, qword [rbp + 16]\n"));
+    assert!(asm.contains("\tmovq xmm7, "));
+    assert!(asm.contains("\tcall f\n\tadd rsp, 16\n"));
+}
+
+#[test]
+fn callee_saved_registers_are_restored_before_returning() {
+    let asm = compile(SUM7);
+    // The spilled param and the saved registers share a frame that keeps the
+    // stack aligned
+    let body = asm
+        .split("sum7:\n\tpush rbp\n\tmov rbp, rsp\n\tsub rsp, 64\n")
+        .nth(1)
+        .unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    let leave = lines.iter().position(|line| *line == "\tleave").unwrap();
+    for (i, register) in ["rbx", "r12", "r13", "r14", "r15"].iter().enumerate() {
+        // Saved before anything else, restored right before leaving
+        let slot = format!("qword [rbp - {}]", 32 + 8 * i);
+        assert_eq!(lines[i], format!("\tmov {}, {}", slot, register));
+        assert_eq!(
+            lines[leave - 5 + i],
+            format!("\tmov {}, {}", register, slot)
+        );
+    }
+}
+
+#[test]
+fn bodies_evaluate_every_expression_and_return_the_last() {
+    let asm = compile(
+        "(module
+           (def f (x)
+             (set! x (+ x 1))
+             (begin (print x) (set! x (* x 2)) x))
+           (def main ()
+             (let ((y 1)) (set! y 7) (progn 3 y))))",
+    );
+    let f = &asm[asm.find("f:").unwrap()..asm.find("program_main:").unwrap()];
+    // The first assignment is printed, the second is read back as the value
+    let first = f.find("\tadd rax, rcx\n\tmov rbx, rax\n").unwrap();
+    let print = f.find("\tcall printf wrt ..plt\n").unwrap();
+    let second = f.find("\timul rax, rcx\n\tmov rbx, rax\n").unwrap();
+    assert!(first < print && print < second);
+    assert!(f[second..].contains("\tmov rbx, rax\n\tmov rax, rbx\n\tmov rbx, qword [rbp - 8]\n"));
+
+    // The `3` is evaluated and dropped, `y` is the value
+    let main = &asm[asm.find("program_main:").unwrap()..];
+    assert!(main.contains("\tmov rax, 7\n\tmov rbx, rax\n\tmov rax, 3\n\tmov rax, rbx\n"));
+}
+
+#[test]
+fn string_constants_are_relocated_outside_of_read_only_data() {
+    let asm = compile(
+        "(module
+           (defconst greeting \"hi\")
+           (defconst answer 42)
+           (defvar name \"x\")
+           (def main () (print greeting) (print name) answer))",
+    );
+    let sections = &asm[asm.find("\tSECTION .rodata\n").unwrap()..];
+    // Only the address of the string needs a section the dynamic linker can
+    // write to
+    assert!(sections.contains(
+        "answer.global: dq 42\nstr2: db 120, 0\n\n\
+         \tSECTION .data.rel.ro progbits alloc noexec write align=8\n\n\
+         greeting.global: dq str0\n\n\
+         \tSECTION .data\n\n\
+         name.global: dq str2\n"
+    ));
+}
+
+#[test]
+fn conditionals_branch_over_the_bodies_they_skip() {
+    let branches = |test: &str, then: &str, other: &str| {
+        format!(
+            "\ttest rax, rax\n\t{} .Lelse1\n\tmov rax, {}\n\tjmp .Lendif2\n\
+             .Lelse1:\n\tmov rax, {}\n.Lendif2:\n",
+            test, then, other
+        )
+    };
+
+    // A missing else branch gives zero
+    let asm = compile("(def f (b:bool) (if b 1))");
+    assert!(asm.contains(&branches("jz", "1", "0")));
+    let asm = compile("(def f (b:bool) (when b 1))");
+    assert!(asm.contains(&branches("jz", "1", "0")));
+    let asm = compile("(def f (b:bool) (unless b 1))");
+    assert!(asm.contains(&branches("jnz", "1", "0")));
+    let asm = compile("(def f (b:bool) (cond (b 1) (else 2)))");
+    assert!(asm.contains(&branches("jz", "1", "2")));
+}
+
+#[test]
+fn logic_operations_short_circuit_bools_and_fold_integers() {
+    // The second operand is skipped once the first decides the result
+    let asm = compile("(def f:bool (a:bool b:bool) (and a b))");
+    assert!(asm.contains("\ttest rax, rax\n\tjz .Landend1\n\tmov rax, r12\n.Landend1:\n"));
+    let asm = compile("(def f:bool (a:bool b:bool) (or a b))");
+    assert!(asm.contains("\ttest rax, rax\n\tjnz .Lorend1\n\tmov rax, r12\n.Lorend1:\n"));
+
+    // Integers are combined bitwise, like the LLVM backend does
+    let asm = compile("(def f () (and 6 3))");
+    assert!(asm.contains("\tmov rax, 6\n\tmov rcx, 3\n\tand rax, rcx\n"));
+    let asm = compile("(def f (x) (or x 3))");
+    assert!(asm.contains("\tor rax, rcx\n"));
+    assert!(!asm.contains(".Lorend"));
+}
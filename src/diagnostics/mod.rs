@@ -0,0 +1,270 @@
+#[cfg(test)]
+mod tests;
+
+use crate::parser::{ParseError, ParseErrorKind, Span};
+use std::str::FromStr;
+
+// A compile error with an optional location in the source program, rendered
+// by `Renderer` with the offending line underlined.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    pub label: Option<String>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error<T>(code: &'static str, message: T) -> Self
+    where
+        T: Into<String>,
+    {
+        Diagnostic {
+            code,
+            message: message.into(),
+            span: None,
+            label: None,
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_label<T>(mut self, label: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_note<T>(mut self, note: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help<T>(mut self, help: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.help = Some(help.into());
+        self
+    }
+
+    // Adds a "did you mean" help when one of `candidates` is close to `name`
+    pub fn with_suggestion<'a, I>(self, name: &str, candidates: I) -> Self
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        match suggest(name, candidates) {
+            Some(candidate) => self.with_help(format!("did you mean `{}`?", candidate)),
+            None => self,
+        }
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(error: ParseError) -> Self {
+        let (code, label) = match &error.kind {
+            ParseErrorKind::UnbalancedParen => ("E0001", "this `(` is never closed"),
+            ParseErrorKind::UnexpectedCloseParen => ("E0002", "no matching `(`"),
+            ParseErrorKind::UnexpectedEof => ("E0003", "expected an expression"),
+            ParseErrorKind::InvalidLiteral(_) => ("E0004", "not a valid number"),
+            ParseErrorKind::IntegerOutOfRange(..) => ("E0009", "out of range"),
+            ParseErrorKind::UnterminatedComment => ("E0005", "comment starts here"),
+            ParseErrorKind::EmptyDatumComment => ("E0006", "expected an expression after this"),
+            ParseErrorKind::UnterminatedString => ("E0007", "string starts here"),
+            ParseErrorKind::InvalidEscape(_) => ("E0008", "unknown escape"),
+            ParseErrorKind::EmptyQuote(_) => ("E0010", "expected an expression after this"),
+        };
+        let diagnostic = Diagnostic::error(code, error.to_string())
+            .with_span(error.span)
+            .with_label(label);
+        match error.kind {
+            ParseErrorKind::InvalidEscape(_) => {
+                diagnostic.with_help(r#"valid escapes are \n, \t, \r, \0, \", \\ and \u{...}"#)
+            }
+            ParseErrorKind::UnexpectedCloseParen => {
+                diagnostic.with_help("remove it, or look for a missing `(` before it")
+            }
+            _ => diagnostic,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+    fn from_str(choice: &str) -> Result<Self, Self::Err> {
+        match choice {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("Unsupported color choice: {}", choice)),
+        }
+    }
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// Formats diagnostics against the source they were produced from
+pub struct Renderer<'a> {
+    path: &'a str,
+    source: &'a str,
+    color: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(path: &'a str, source: &'a str, color: bool) -> Self {
+        Renderer {
+            path,
+            source,
+            color,
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let mut out = format!(
+            "{}: {}\n",
+            self.paint(RED, &format!("error[{}]", diagnostic.code)),
+            self.paint(BOLD, &diagnostic.message)
+        );
+
+        let gutter = diagnostic
+            .span
+            .map_or(0, |span| span.line.to_string().len());
+        let pad = " ".repeat(gutter);
+        let bar = self.paint(BLUE, "|");
+
+        match diagnostic.span {
+            Some(span) => {
+                let line = self.source.lines().nth(span.line - 1).unwrap_or("");
+                out.push_str(&format!(
+                    "{}{} {}:{}\n",
+                    pad,
+                    self.paint(BLUE, "-->"),
+                    self.path,
+                    span
+                ));
+                out.push_str(&format!("{} {}\n", pad, bar));
+                out.push_str(&format!(
+                    "{} {} {}\n",
+                    self.paint(BLUE, &span.line.to_string()),
+                    bar,
+                    line
+                ));
+                let (indent, width) = underline(line, span);
+                let mut marker = self.paint(RED, &"^".repeat(width));
+                if let Some(label) = &diagnostic.label {
+                    marker.push(' ');
+                    marker.push_str(&self.paint(RED, label));
+                }
+                out.push_str(&format!("{} {} {}{}\n", pad, bar, indent, marker));
+            }
+            None => {
+                out.push_str(&format!(
+                    "{}{} {}\n",
+                    pad,
+                    self.paint(BLUE, "-->"),
+                    self.path
+                ));
+            }
+        }
+
+        for note in &diagnostic.notes {
+            out.push_str(&format!(
+                "{} {} {}\n",
+                pad,
+                self.paint(CYAN, "= note:"),
+                note
+            ));
+        }
+        if let Some(help) = &diagnostic.help {
+            out.push_str(&format!(
+                "{} {} {}\n",
+                pad,
+                self.paint(CYAN, "= help:"),
+                help
+            ));
+        }
+
+        out
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+// Whitespace leading up to the span (keeping tabs so the caret lines up) and
+// the number of carets to draw, clipped to the first line of the span
+fn underline(line: &str, span: Span) -> (String, usize) {
+    let indent = line
+        .chars()
+        .take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = line
+        .chars()
+        .skip(span.column - 1)
+        .scan(0, |len, c| {
+            *len += c.len_utf8();
+            Some(*len)
+        })
+        .take_while(|len| *len <= span.end - span.start)
+        .count();
+    (indent, width.max(1))
+}
+
+// Closest candidate to `name` by edit distance, if any is close enough to be
+// a plausible typo. Names shorter than three characters get no suggestion,
+// as any other short name would be "close".
+pub fn suggest<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let threshold = name.chars().count() / 3;
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance > 0 && *distance <= threshold)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,61 @@
+use super::*;
+use crate::parser::parse;
+
+#[test]
+fn render_underlines_the_span_with_label_and_help() {
+    let source = "(module\n    (plus-tw 1 2))\n";
+    let span = Span {
+        start: 12,
+        end: 25,
+        line: 2,
+        column: 5,
+    };
+    let diagnostic = Diagnostic::error("E0102", "attempt to call undefined function `plus-tw`")
+        .with_span(span)
+        .with_label("not found in this scope")
+        .with_suggestion("plus-tw", vec!["plus-two", "main"]);
+
+    assert_eq!(
+        Renderer::new("test.ulisp", source, false).render(&diagnostic),
+        "error[E0102]: attempt to call undefined function `plus-tw`
+ --> test.ulisp:2:5
+  |
+2 |     (plus-tw 1 2))
+  |     ^^^^^^^^^^^^^ not found in this scope
+  = help: did you mean `plus-two`?
+"
+    );
+}
+
+#[test]
+fn render_parse_errors() {
+    let diagnostic = Diagnostic::from(parse(")").unwrap_err().remove(0));
+    assert_eq!(
+        Renderer::new("test.ulisp", ")", false).render(&diagnostic),
+        "error[E0002]: unexpected `)`
+ --> test.ulisp:1:1
+  |
+1 | )
+  | ^ no matching `(`
+  = help: remove it, or look for a missing `(` before it
+"
+    );
+}
+
+#[test]
+fn render_keeps_tabs_before_the_caret() {
+    let diagnostic = Diagnostic::error("E0101", "undefined").with_span(Span {
+        start: 2,
+        end: 3,
+        line: 1,
+        column: 3,
+    });
+    let rendered = Renderer::new("test.ulisp", "\t(x)", false).render(&diagnostic);
+    assert!(rendered.contains("1 | \t(x)\n  | \t ^\n"));
+}
+
+#[test]
+fn suggest_picks_the_closest_name() {
+    assert_eq!(suggest("fibb", vec!["fib", "main"]), Some("fib"));
+    assert_eq!(suggest("x", vec!["fib", "main"]), None);
+}
@@ -0,0 +1,581 @@
+#[cfg(test)]
+mod tests;
+
+use crate::diagnostics::Diagnostic;
+use crate::parser::{Expression, ExpressionKind, Span};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Expansions a single form can go through, which stops a macro expanding to
+// a call of itself forever
+const MAX_EXPANSIONS: usize = 256;
+
+// Functions a macro body can call, on the values of their arguments
+const BUILTINS: &[&str] = &[
+    "list", "cons", "car", "first", "cdr", "rest", "append", "length", "null?", "list?", "symbol?",
+    "=", "<", "+", "-", "*", "not", "gensym",
+];
+
+// Macro defined by `(defmacro name (params... &rest rest) body...)`
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    // Param bound to the list of the arguments after the other ones
+    rest: Option<String>,
+    body: Vec<Expression>,
+}
+
+// Diagnostics are boxed so results stay as small as the values they hold
+type ExpandResult<T> = Result<T, Box<Diagnostic>>;
+
+// Values of the variables of a macro body
+type Env = HashMap<String, Expression>;
+
+// Replaces the calls of macros in a `(module ...)` with the code their body
+// evaluates to, dropping the `defmacro` forms. Like functions, macros can be
+// used before the form defining them. Expansions are expanded again, so a
+// macro can expand to a call of another one or of itself.
+pub fn expand(program: Expression) -> Result<Expression, Vec<Diagnostic>> {
+    let span = program.span;
+    let forms = match program.kind {
+        ExpressionKind::List(forms) => forms,
+        kind => return Ok(Expression::new(kind, span)),
+    };
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        symbols: 0,
+    };
+    let mut errors = Vec::new();
+
+    let (definitions, forms): (Vec<_>, Vec<_>) = forms.into_iter().partition(is_defmacro);
+    for definition in &definitions {
+        match split_defmacro(definition) {
+            Ok(definition) => {
+                expander
+                    .macros
+                    .insert(definition.name.clone(), Rc::new(definition));
+            }
+            Err(error) => errors.push(*error),
+        }
+    }
+
+    // A broken form doesn't stop the rest of the module from being expanded
+    let mut expanded = Vec::with_capacity(forms.len());
+    for form in forms {
+        match expander.expand(form, 0) {
+            Ok(form) => expanded.push(form),
+            Err(error) => errors.push(*error),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Expression::new(ExpressionKind::List(expanded), span))
+}
+
+fn is_defmacro(form: &Expression) -> bool {
+    head(form) == Some("defmacro")
+}
+
+// Name the list `expression` starts with, if any
+fn head(expression: &Expression) -> Option<&str> {
+    match &expression.kind {
+        ExpressionKind::List(items) => match items.first().map(|item| &item.kind) {
+            Some(ExpressionKind::Symbol(name)) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn split_defmacro(definition: &Expression) -> ExpandResult<Macro> {
+    let malformed = |span: Span, label: &str| {
+        Diagnostic::error("E0301", "malformed macro definition")
+            .with_span(span)
+            .with_label(label)
+            .with_help("define it as `(defmacro name (params...) body...)`")
+    };
+    let items = match &definition.kind {
+        ExpressionKind::List(items) if items.len() >= 4 => items,
+        _ => return Err(malformed(definition.span, "expected a name, params and a body").into()),
+    };
+    let name = match &items[1].kind {
+        ExpressionKind::Symbol(name) => name.clone(),
+        _ => return Err(malformed(items[1].span, "expected a name").into()),
+    };
+    let params = match &items[2].kind {
+        ExpressionKind::List(params) => params,
+        _ => return Err(malformed(items[2].span, "expected a list of params").into()),
+    };
+    let mut names = Vec::with_capacity(params.len());
+    for param in params {
+        match &param.kind {
+            ExpressionKind::Symbol(name) => names.push(name.clone()),
+            _ => return Err(malformed(param.span, "expected a param name").into()),
+        }
+    }
+    let rest = match names.iter().position(|name| name == "&rest") {
+        Some(i) if i + 2 == names.len() => {
+            let rest = names.pop();
+            names.pop();
+            rest
+        }
+        Some(i) => {
+            return Err(
+                malformed(params[i].span, "`&rest` must be followed by the last param").into(),
+            )
+        }
+        None => None,
+    };
+    Ok(Macro {
+        name,
+        params: names,
+        rest,
+        body: items[3..].to_vec(),
+    })
+}
+
+struct Expander {
+    macros: HashMap<String, Rc<Macro>>,
+    // Number of symbols made by `gensym` so far, keeping them unique
+    symbols: usize,
+}
+
+impl Expander {
+    // Expands `expression`, which came from `depth` expansions
+    fn expand(&mut self, expression: Expression, depth: usize) -> ExpandResult<Expression> {
+        let span = expression.span;
+        let items = match expression.kind {
+            ExpressionKind::List(items) => items,
+            kind => return Ok(Expression::new(kind, span)),
+        };
+        let called = match items.first().map(|item| &item.kind) {
+            Some(ExpressionKind::Symbol(name)) => self.macros.get(name).cloned(),
+            _ => None,
+        };
+        if let Some(called) = called {
+            if depth == MAX_EXPANSIONS {
+                return Err(Diagnostic::error(
+                    "E0306",
+                    format!("recursion limit reached while expanding `{}`", called.name),
+                )
+                .with_span(span)
+                .with_label(format!("expanded {} times", MAX_EXPANSIONS))
+                .with_help("make sure the macro stops expanding to a call of itself")
+                .into());
+            }
+            check_arity(&called, &items[1..], span)?;
+            let expansion = self.call(&called, &items[1..], span).map_err(|error| {
+                error.with_note(format!("while expanding `{}` at {}", called.name, span))
+            })?;
+            return self.expand(expansion, depth + 1);
+        }
+        let items = items
+            .into_iter()
+            .map(|item| self.expand(item, depth))
+            .collect::<Result<_, _>>()?;
+        Ok(Expression::new(ExpressionKind::List(items), span))
+    }
+
+    // Evaluates the body of a macro with its params bound to the expressions
+    // it's called with, unevaluated
+    fn call(
+        &mut self,
+        called: &Macro,
+        args: &[Expression],
+        site: Span,
+    ) -> ExpandResult<Expression> {
+        let mut env: Env = called.params.iter().cloned().zip(args.to_vec()).collect();
+        if let Some(rest) = &called.rest {
+            let rest_args = ExpressionKind::List(args[called.params.len()..].to_vec());
+            env.insert(rest.clone(), Expression::new(rest_args, site));
+        }
+        self.evaluate_body(&called.body, &env, site)
+    }
+
+    fn evaluate_body(
+        &mut self,
+        body: &[Expression],
+        env: &Env,
+        site: Span,
+    ) -> ExpandResult<Expression> {
+        let mut value = list(Vec::new(), site);
+        for expression in body {
+            value = self.evaluate(expression, env, site)?;
+        }
+        Ok(value)
+    }
+
+    // Value of an expression of a macro body. Code it builds gets the span of
+    // the call being expanded, `site`.
+    fn evaluate(
+        &mut self,
+        expression: &Expression,
+        env: &Env,
+        site: Span,
+    ) -> ExpandResult<Expression> {
+        let items = match &expression.kind {
+            ExpressionKind::Symbol(name) => {
+                return env.get(name).cloned().ok_or_else(|| {
+                    Diagnostic::error(
+                        "E0303",
+                        format!("attempt to reference undefined variable `{}`", name),
+                    )
+                    .with_span(expression.span)
+                    .with_label("not a param or binding of the macro")
+                    .with_suggestion(name, env.keys().map(String::as_str))
+                    .into()
+                })
+            }
+            ExpressionKind::List(items) if !items.is_empty() => items,
+            _ => return Ok(relocate(expression, site)),
+        };
+        let args = &items[1..];
+        let arity = |expected: usize| -> ExpandResult<()> {
+            if args.len() == expected {
+                Ok(())
+            } else {
+                Err(wrong_arguments(expression, &arguments(expected)).into())
+            }
+        };
+        let name = match &items[0].kind {
+            ExpressionKind::Symbol(name) => name.as_str(),
+            _ => return Err(unknown_function(&items[0]).into()),
+        };
+        match name {
+            "quote" => {
+                arity(1)?;
+                Ok(relocate(&args[0], site))
+            }
+            "quasiquote" => {
+                arity(1)?;
+                self.quasiquote(&args[0], env, site, 1)
+            }
+            "unquote" | "unquote-splicing" => Err(Diagnostic::error(
+                "E0305",
+                format!("`{}` outside of a quasiquote", name),
+            )
+            .with_span(expression.span)
+            .with_label("not inside a `quasiquote` template")
+            .into()),
+            "if" => {
+                if args.len() != 2 && args.len() != 3 {
+                    return Err(wrong_arguments(expression, "2 or 3 arguments").into());
+                }
+                let test = self.evaluate(&args[0], env, site)?;
+                if !matches!(test.kind, ExpressionKind::Boolean(false)) {
+                    self.evaluate(&args[1], env, site)
+                } else if let Some(otherwise) = args.get(2) {
+                    self.evaluate(otherwise, env, site)
+                } else {
+                    Ok(Expression::new(ExpressionKind::Boolean(false), site))
+                }
+            }
+            "let" => {
+                let bindings = match args.first().map(|bindings| &bindings.kind) {
+                    Some(ExpressionKind::List(bindings)) if args.len() >= 2 => bindings,
+                    _ => return Err(wrong_arguments(expression, "bindings and a body").into()),
+                };
+                let mut inner = env.clone();
+                for binding in bindings {
+                    match &binding.kind {
+                        ExpressionKind::List(pair) if pair.len() == 2 => {
+                            let value = self.evaluate(&pair[1], env, site)?;
+                            match &pair[0].kind {
+                                ExpressionKind::Symbol(name) => inner.insert(name.clone(), value),
+                                _ => {
+                                    return Err(
+                                        wrong_arguments(binding, "a name and a value").into()
+                                    )
+                                }
+                            };
+                        }
+                        _ => return Err(wrong_arguments(binding, "a name and a value").into()),
+                    }
+                }
+                self.evaluate_body(&args[1..], &inner, site)
+            }
+            "begin" | "progn" => self.evaluate_body(args, env, site),
+            _ if BUILTINS.contains(&name) => {
+                let values = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg, env, site))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.apply(name, values, expression, site)
+            }
+            _ => Err(unknown_function(&items[0]).into()),
+        }
+    }
+
+    // Value of the template of a quasiquote, nested in `depth` of them
+    fn quasiquote(
+        &mut self,
+        template: &Expression,
+        env: &Env,
+        site: Span,
+        depth: usize,
+    ) -> ExpandResult<Expression> {
+        let items = match &template.kind {
+            ExpressionKind::List(items) => items,
+            _ => return Ok(relocate(template, site)),
+        };
+        let nested = |depth: usize, expander: &mut Expander| {
+            let name = head(template).unwrap();
+            let inner = expander.quasiquote(&items[1], env, site, depth)?;
+            Ok(list(vec![symbol(name, site), inner], site))
+        };
+        match head(template) {
+            Some("unquote") if items.len() == 2 && depth == 1 => {
+                return self.evaluate(&items[1], env, site)
+            }
+            Some("unquote") | Some("unquote-splicing") if items.len() == 2 => {
+                return nested(depth - 1, self)
+            }
+            Some("quasiquote") if items.len() == 2 => return nested(depth + 1, self),
+            _ => {}
+        }
+        let mut values = Vec::with_capacity(items.len());
+        for item in items {
+            match &item.kind {
+                ExpressionKind::List(spliced)
+                    if depth == 1
+                        && spliced.len() == 2
+                        && head(item) == Some("unquote-splicing") =>
+                {
+                    match self.evaluate(&spliced[1], env, site)?.kind {
+                        ExpressionKind::List(elements) => values.extend(elements),
+                        _ => return Err(expected_list("unquote-splicing", item).into()),
+                    }
+                }
+                _ => values.push(self.quasiquote(item, env, site, depth)?),
+            }
+        }
+        Ok(list(values, site))
+    }
+
+    // Applies a builtin to the values of its arguments
+    fn apply(
+        &mut self,
+        name: &str,
+        mut values: Vec<Expression>,
+        call: &Expression,
+        site: Span,
+    ) -> ExpandResult<Expression> {
+        let count = |expected: usize| -> ExpandResult<()> {
+            if values.len() == expected {
+                Ok(())
+            } else {
+                Err(wrong_arguments(call, &arguments(expected)).into())
+            }
+        };
+        let kind = match name {
+            "list" => ExpressionKind::List(values),
+            "cons" => {
+                count(2)?;
+                let mut elements = elements(name, values.pop().unwrap(), call)?;
+                elements.insert(0, values.pop().unwrap());
+                ExpressionKind::List(elements)
+            }
+            "car" | "first" | "cdr" | "rest" => {
+                count(1)?;
+                let mut elements = elements(name, values.pop().unwrap(), call)?;
+                if elements.is_empty() {
+                    return Err(Diagnostic::error(
+                        "E0304",
+                        format!("`{}` expects a non-empty list", name),
+                    )
+                    .with_span(call.span)
+                    .with_label("found an empty list")
+                    .into());
+                }
+                if name == "car" || name == "first" {
+                    return Ok(elements.remove(0));
+                }
+                elements.remove(0);
+                ExpressionKind::List(elements)
+            }
+            "append" => {
+                let mut appended = Vec::new();
+                for value in values {
+                    appended.extend(elements(name, value, call)?);
+                }
+                ExpressionKind::List(appended)
+            }
+            "length" => {
+                count(1)?;
+                let length = elements(name, values.pop().unwrap(), call)?.len();
+                ExpressionKind::Integer(length as i64, None)
+            }
+            "null?" => {
+                count(1)?;
+                ExpressionKind::Boolean(elements(name, values.pop().unwrap(), call)?.is_empty())
+            }
+            "list?" | "symbol?" => {
+                count(1)?;
+                let value = &values[0].kind;
+                ExpressionKind::Boolean(match name {
+                    "list?" => matches!(value, ExpressionKind::List(_)),
+                    _ => matches!(value, ExpressionKind::Symbol(_)),
+                })
+            }
+            "not" => {
+                count(1)?;
+                ExpressionKind::Boolean(matches!(values[0].kind, ExpressionKind::Boolean(false)))
+            }
+            "=" => {
+                count(2)?;
+                ExpressionKind::Boolean(equal(&values[0], &values[1]))
+            }
+            "gensym" => {
+                count(0)?;
+                self.symbols += 1;
+                ExpressionKind::Symbol(format!("gensym.{}", self.symbols))
+            }
+            // Integer arithmetic and comparison
+            _ => {
+                let mut integers = Vec::with_capacity(values.len());
+                for value in &values {
+                    match value.kind {
+                        ExpressionKind::Integer(integer, _) => integers.push(integer),
+                        _ => {
+                            return Err(Diagnostic::error(
+                                "E0304",
+                                format!("`{}` expects integers", name),
+                            )
+                            .with_span(call.span)
+                            .with_label("not every argument is an integer")
+                            .into())
+                        }
+                    }
+                }
+                let overflow = || {
+                    Diagnostic::error("E0304", format!("`{}` overflows", name))
+                        .with_span(call.span)
+                        .with_label("result does not fit in `i64`")
+                };
+                match (name, integers.as_slice()) {
+                    ("<", [left, right]) => ExpressionKind::Boolean(left < right),
+                    ("<", _) => return Err(wrong_arguments(call, "2 arguments").into()),
+                    ("-", [value]) => {
+                        ExpressionKind::Integer(value.checked_neg().ok_or_else(overflow)?, None)
+                    }
+                    ("-", []) => return Err(wrong_arguments(call, "at least 1 argument").into()),
+                    _ => {
+                        let (mut result, rest) = match name {
+                            "+" => (0, &integers[..]),
+                            "*" => (1, &integers[..]),
+                            _ => (integers[0], &integers[1..]),
+                        };
+                        for value in rest {
+                            let next = match name {
+                                "+" => result.checked_add(*value),
+                                "*" => result.checked_mul(*value),
+                                _ => result.checked_sub(*value),
+                            };
+                            result = next.ok_or_else(overflow)?;
+                        }
+                        ExpressionKind::Integer(result, None)
+                    }
+                }
+            }
+        };
+        Ok(Expression::new(kind, site))
+    }
+}
+
+// Error unless a macro is called with as many arguments as it takes
+fn check_arity(called: &Macro, args: &[Expression], site: Span) -> ExpandResult<()> {
+    let expected = called.params.len();
+    if args.len() < expected || (called.rest.is_none() && args.len() > expected) {
+        let expected = if called.rest.is_some() {
+            format!("at least {}", expected)
+        } else {
+            expected.to_string()
+        };
+        return Err(Diagnostic::error(
+            "E0302",
+            format!(
+                "`{}` takes {} arguments, found {}",
+                called.name,
+                expected,
+                args.len()
+            ),
+        )
+        .with_span(site)
+        .with_label("wrong number of arguments")
+        .into());
+    }
+    Ok(())
+}
+
+fn list(items: Vec<Expression>, span: Span) -> Expression {
+    Expression::new(ExpressionKind::List(items), span)
+}
+
+fn symbol(name: &str, span: Span) -> Expression {
+    Expression::new(ExpressionKind::Symbol(name.to_string()), span)
+}
+
+// Elements of a value a builtin needs to be a list
+fn elements(name: &str, value: Expression, call: &Expression) -> ExpandResult<Vec<Expression>> {
+    match value.kind {
+        ExpressionKind::List(elements) => Ok(elements),
+        _ => Err(expected_list(name, call).into()),
+    }
+}
+
+// Copy of code quoted in a macro body, as if written where the macro is
+// called
+fn relocate(expression: &Expression, site: Span) -> Expression {
+    let kind = match &expression.kind {
+        ExpressionKind::List(items) => {
+            ExpressionKind::List(items.iter().map(|item| relocate(item, site)).collect())
+        }
+        kind => kind.clone(),
+    };
+    Expression::new(kind, site)
+}
+
+// Whether two values are the same code, wherever they were written
+fn equal(left: &Expression, right: &Expression) -> bool {
+    match (&left.kind, &right.kind) {
+        (ExpressionKind::List(left), ExpressionKind::List(right)) => {
+            left.len() == right.len() && left.iter().zip(right).all(|(l, r)| equal(l, r))
+        }
+        (ExpressionKind::Symbol(left), ExpressionKind::Symbol(right)) => left == right,
+        (ExpressionKind::String(left), ExpressionKind::String(right)) => left == right,
+        (ExpressionKind::Integer(left, _), ExpressionKind::Integer(right, _)) => left == right,
+        (ExpressionKind::Float(left), ExpressionKind::Float(right)) => left == right,
+        (ExpressionKind::Boolean(left), ExpressionKind::Boolean(right)) => left == right,
+        _ => false,
+    }
+}
+
+fn unknown_function(head: &Expression) -> Diagnostic {
+    Diagnostic::error(
+        "E0303",
+        "attempt to call an unknown function in a macro body",
+    )
+    .with_span(head.span)
+    .with_label("not a builtin available at compile time")
+    .with_help(format!("macro bodies can call `{}`", BUILTINS.join("`, `")))
+}
+
+fn wrong_arguments(form: &Expression, expected: &str) -> Diagnostic {
+    Diagnostic::error("E0304", "malformed expression in a macro body")
+        .with_span(form.span)
+        .with_label(format!("expected {}", expected))
+}
+
+fn arguments(count: usize) -> String {
+    match count {
+        1 => "1 argument".to_string(),
+        _ => format!("{} arguments", count),
+    }
+}
+
+fn expected_list(name: &str, form: &Expression) -> Diagnostic {
+    Diagnostic::error("E0304", format!("`{}` expects a list", name))
+        .with_span(form.span)
+        .with_label("not a list")
+}
@@ -0,0 +1,67 @@
+use super::*;
+use crate::parser::parse;
+
+// Expanded program written back as code, without the module around it
+fn expand_program(program: &str) -> String {
+    match expand(parse(program).unwrap()).unwrap().kind {
+        ExpressionKind::List(forms) => forms[1..].iter().map(code).collect::<Vec<_>>().join(" "),
+        kind => panic!("expected a module, got {:?}", kind),
+    }
+}
+
+fn code(expression: &Expression) -> String {
+    match &expression.kind {
+        ExpressionKind::List(items) => {
+            let items: Vec<String> = items.iter().map(code).collect();
+            format!("({})", items.join(" "))
+        }
+        ExpressionKind::Symbol(symbol) => symbol.clone(),
+        ExpressionKind::String(string) => format!("{:?}", string),
+        ExpressionKind::Integer(integer, _) => integer.to_string(),
+        ExpressionKind::Float(float) => float.to_string(),
+        ExpressionKind::Boolean(boolean) => boolean.to_string(),
+    }
+}
+
+#[test]
+fn expand_replaces_macro_calls_with_their_quasiquote_templates() {
+    let program = "
+        (defmacro -> (x &rest forms)
+          (if (null? forms)
+              x
+              (let ((form (car forms)))
+                `(-> ,(if (list? form) `(,(car form) ,x ,@(cdr form)) (list form x))
+                     ,@(cdr forms)))))
+        (def f (n) (-> n inc (* 2) (- 1)))";
+    assert_eq!(expand_program(program), "(def f (n) (- (* (inc n) 2) 1))");
+}
+
+#[test]
+fn expand_gives_built_code_the_span_of_the_call() {
+    let program = "(defmacro twice (x) `(+ ,x ,x))\n(def f (n) (twice n))";
+    let expanded = expand(parse(program).unwrap()).unwrap();
+    let call = match &expanded.kind {
+        ExpressionKind::List(forms) => match &forms[1].kind {
+            ExpressionKind::List(items) => items[3].clone(),
+            kind => panic!("expected a definition, got {:?}", kind),
+        },
+        kind => panic!("expected a module, got {:?}", kind),
+    };
+    let spans: Vec<(usize, usize)> = match &call.kind {
+        ExpressionKind::List(items) => items.iter().map(|i| (i.span.line, i.span.column)).collect(),
+        kind => panic!("expected a call, got {:?}", kind),
+    };
+    // `+` comes from the template, the arguments from the call
+    assert_eq!(spans, vec![(2, 12), (2, 19), (2, 19)]);
+    assert_eq!((call.span.line, call.span.column), (2, 12));
+}
+
+#[test]
+fn expand_stops_macros_expanding_to_themselves() {
+    let program = "(defmacro forever () '(forever)) (def main () (forever))";
+    let errors = expand(parse(program).unwrap()).unwrap_err();
+    assert_eq!(
+        errors.iter().map(|error| error.code).collect::<Vec<_>>(),
+        vec!["E0306"]
+    );
+}
@@ -1,15 +1,21 @@
 extern crate structopt;
 
 mod backend;
+mod diagnostics;
+mod expander;
 mod parser;
 
 use backend::llvm::Scope as llvm_Scope;
 use backend::x86::Scope as x86_Scope;
 use backend::{llvm, x86, Backend, BackendOpt};
+use diagnostics::{ColorChoice, Diagnostic, Renderer};
+use expander::expand;
 use parser::{parse, Expression};
+use std::env;
 use std::fs;
-use std::io::Read;
+use std::io::{self, IsTerminal, Read};
 use std::path;
+use std::process;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -20,9 +26,12 @@ struct Opt {
     output: path::PathBuf,
     #[structopt(short = "b", long = "backend", default_value = "llvm")]
     backend: BackendOpt,
+    #[structopt(long = "color", default_value = "auto")]
+    color: ColorChoice,
 }
 
 type X86 = Box<dyn Backend<S = x86_Scope>>;
+#[allow(clippy::upper_case_acronyms)]
 type LLVM = Box<dyn Backend<S = llvm_Scope>>;
 
 fn main() {
@@ -31,31 +40,76 @@ fn main() {
     let input = opt.input.to_str().unwrap();
     let output = opt.output.to_str().unwrap();
     let backend = opt.backend;
+    let color = use_color(opt.color);
 
-    let code = read_input(input);
-    let ast = parse(&code);
+    let code = match read_input(input) {
+        Ok(code) => code,
+        Err(diagnostic) => report(&Renderer::new(input, "", color), &[*diagnostic]),
+    };
+    let renderer = Renderer::new(input, &code, color);
 
-    match backend {
-        BackendOpt::X86 => run_x86_backend(x86::new(), ast, input, output),
-        BackendOpt::LLVM => run_llvm_backend(llvm::new(), ast, input, output),
+    let result = parse(&code)
+        .map_err(|errors| errors.into_iter().map(Diagnostic::from).collect())
+        .and_then(expand)
+        .and_then(|ast| match backend {
+            BackendOpt::X86 => run_x86_backend(x86::new(), ast, input, output),
+            BackendOpt::LLVM => run_llvm_backend(llvm::new(), ast, input, output),
+        });
+
+    if let Err(diagnostics) = result {
+        report(&renderer, &diagnostics);
     }
 }
 
-fn run_x86_backend(mut backend: X86, ast: Expression, input: &str, output: &str) {
-    let asm = backend.compile(&ast);
-    backend.build(asm, input, output);
+fn run_x86_backend(
+    mut backend: X86,
+    ast: Expression,
+    input: &str,
+    output: &str,
+) -> Result<(), Vec<Diagnostic>> {
+    let asm = backend.compile(&ast)?;
+    backend
+        .build(asm, input, output)
+        .map_err(|error| vec![*error])
+}
+
+fn run_llvm_backend(
+    mut backend: LLVM,
+    ast: Expression,
+    input: &str,
+    output: &str,
+) -> Result<(), Vec<Diagnostic>> {
+    let asm = backend.compile(&ast)?;
+    backend
+        .build(asm, input, output)
+        .map_err(|error| vec![*error])
+}
+
+fn report(renderer: &Renderer, diagnostics: &[Diagnostic]) -> ! {
+    for diagnostic in diagnostics {
+        eprintln!("{}", renderer.render(diagnostic));
+    }
+    if diagnostics.len() > 1 {
+        eprintln!("aborting due to {} previous errors", diagnostics.len());
+    }
+    process::exit(1);
 }
 
-fn run_llvm_backend(mut backend: LLVM, ast: Expression, input: &str, output: &str) {
-    let asm = backend.compile(&ast);
-    backend.build(asm, &input, &output);
+fn use_color(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none(),
+    }
 }
 
-fn read_input(input: &str) -> String {
-    let mut input = fs::File::open(input).expect("failed open input file");
+fn read_input(input: &str) -> Result<String, Box<Diagnostic>> {
     let mut code = String::new();
-    input
-        .read_to_string(&mut code)
-        .expect("failed read input file");
-    code
+    fs::File::open(input)
+        .and_then(|mut file| file.read_to_string(&mut code))
+        .map_err(|error| {
+            Diagnostic::error("E0200", format!("failed to read `{}`", input))
+                .with_note(error.to_string())
+        })?;
+    Ok(code)
 }
@@ -1,59 +1,631 @@
+#[cfg(test)]
+mod tests;
+
+use std::fmt;
+
+// Location of a piece of source code. `start` and `end` are byte offsets
+// into the program text, `line` and `column` (both 1-based) point to `start`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    // Span covering from the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
 #[derive(Clone, Debug)]
-pub enum Expression {
+pub enum ExpressionKind {
     List(Vec<Expression>),
     // Atoms:
     Symbol(String),
-    Integer(i32),
-    Float(f32),
-    #[allow(dead_code)]
+    String(String),
+    // An integer and the type given by its suffix, if any. `u64` values are
+    // stored as their two's complement bit pattern.
+    Integer(i64, Option<IntType>),
+    Float(f64),
     Boolean(bool),
 }
 
-pub fn parse(program: &str) -> Expression {
-    let mut tokens = tokenize(program);
-    read_from_tokens(&mut tokens)
+// Width and signedness of an integer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntType {
+    I8,
+    I16,
+    I32,
+    I64,
+    U64,
+}
+
+impl IntType {
+    pub const ALL: [IntType; 5] = [
+        IntType::I8,
+        IntType::I16,
+        IntType::I32,
+        IntType::I64,
+        IntType::U64,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            IntType::I8 => "i8",
+            IntType::I16 => "i16",
+            IntType::I32 => "i32",
+            IntType::I64 => "i64",
+            IntType::U64 => "u64",
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            IntType::I8 => 8,
+            IntType::I16 => 16,
+            IntType::I32 => 32,
+            IntType::I64 | IntType::U64 => 64,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        self != IntType::U64
+    }
+
+    // Whether `value` can be represented by this type
+    pub fn fits(self, value: i128) -> bool {
+        if self.is_signed() {
+            let max = (1i128 << (self.bits() - 1)) - 1;
+            -max - 1 <= value && value <= max
+        } else {
+            0 <= value && value < 1i128 << self.bits()
+        }
+    }
+}
+
+impl fmt::Display for IntType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Self {
+        Expression { kind, span }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    // A `(` that is never closed
+    UnbalancedParen,
+    // A `)` without a matching `(`
+    UnexpectedCloseParen,
+    // The program ended where an expression was expected
+    UnexpectedEof,
+    // A token that looks like a number but can't be read as one
+    InvalidLiteral(String),
+    // An integer literal too large for its type
+    IntegerOutOfRange(String, IntType),
+    // A `#|` block comment that is never closed
+    UnterminatedComment,
+    // A `#;` datum comment with no expression after it
+    EmptyDatumComment,
+    // A string literal missing its closing `"`
+    UnterminatedString,
+    // An unknown or malformed `\` escape sequence in a string literal
+    InvalidEscape(String),
+    // A `'`, `` ` ``, `,` or `,@` with no expression after it
+    EmptyQuote(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, span: Span) -> Self {
+        ParseError { kind, span }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnbalancedParen => write!(f, "unclosed `(`"),
+            ParseErrorKind::UnexpectedCloseParen => write!(f, "unexpected `)`"),
+            ParseErrorKind::UnexpectedEof => write!(f, "unexpected end of file"),
+            ParseErrorKind::InvalidLiteral(literal) => {
+                write!(f, "invalid numeric literal `{}`", literal)
+            }
+            ParseErrorKind::IntegerOutOfRange(literal, ty) => {
+                write!(f, "integer literal `{}` does not fit in `{}`", literal, ty)
+            }
+            ParseErrorKind::UnterminatedComment => write!(f, "unterminated block comment"),
+            ParseErrorKind::EmptyDatumComment => {
+                write!(f, "datum comment is not followed by an expression")
+            }
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string literal"),
+            ParseErrorKind::InvalidEscape(escape) => {
+                write!(f, "invalid escape sequence `{}`", escape)
+            }
+            ParseErrorKind::EmptyQuote(quote) => {
+                write!(f, "`{}` is not followed by an expression", quote)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Atom,
+    // A string literal, with its escape sequences already resolved
+    String(String),
+    // `#;`, comments out the expression that follows
+    DatumComment,
+    // `'`, `` ` ``, `,` or `,@`, short for the form named here around the
+    // expression that follows
+    Quote(&'static str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    span: Span,
+}
+
+// Parse every top-level form of a program into a `(module ...)` expression,
+// recovering from errors where possible so all of them can be reported at
+// once. A program made of a single `(module ...)` form is returned as is.
+pub fn parse(program: &str) -> Result<Expression, Vec<ParseError>> {
+    let mut errors = Vec::new();
+    let mut tokens = tokenize(program, &mut errors);
+    let eof = end_of(program);
+
+    let mut forms = Vec::new();
+    loop {
+        let result = match tokens.first() {
+            None => break,
+            // Skipped here, as read_from_tokens expects a form after it
+            Some(t) if t.kind == TokenKind::DatumComment => {
+                skip_datum_comment(&mut tokens, eof, &mut errors)
+            }
+            Some(_) => read_from_tokens(&mut tokens, eof, &mut errors).map(|form| forms.push(form)),
+        };
+        if let Err(error) = result {
+            errors.push(error);
+        }
+    }
+    if forms.is_empty() && errors.is_empty() {
+        errors.push(ParseError::new(ParseErrorKind::UnexpectedEof, eof));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if forms.len() == 1 && is_module(&forms[0]) {
+        return Ok(forms.remove(0));
+    }
+    let start = Span {
+        start: 0,
+        end: 0,
+        line: 1,
+        column: 1,
+    };
+    let mut items = vec![Expression::new(
+        ExpressionKind::Symbol("module".to_string()),
+        start,
+    )];
+    items.extend(forms);
+    Ok(Expression::new(ExpressionKind::List(items), start.to(eof)))
+}
+
+fn is_module(expression: &Expression) -> bool {
+    match &expression.kind {
+        ExpressionKind::List(items) => match items.first() {
+            Some(head) => matches!(&head.kind, ExpressionKind::Symbol(name) if name == "module"),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+// Empty span pointing just past the last character of the program
+fn end_of(program: &str) -> Span {
+    let line = program.matches('\n').count() + 1;
+    let line_start = program.rfind('\n').map_or(0, |i| i + 1);
+    Span {
+        start: program.len(),
+        end: program.len(),
+        line,
+        column: program[line_start..].chars().count() + 1,
+    }
 }
 
-// Convert a string of characters into a list of tokens
-fn tokenize(string: &str) -> Vec<String> {
-    string
-        .trim()
-        .replace('(', "( ")
-        .replace(')', " )")
-        .replace("\n", "")
-        .split(' ')
-        .filter(|s| *s != "") // Empty list "()" generates List([Symbol("")])
-        .map(std::borrow::ToOwned::to_owned)
-        .collect()
+// Walks the program text keeping track of the current line and column
+struct Cursor<'a> {
+    source: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
 }
 
-// Read an expression from a sequence of tokens
-fn read_from_tokens(mut tokens: &mut Vec<String>) -> Expression {
-    if tokens.is_empty() {
-        panic!("Unexpected EOF");
+impl<'a> Cursor<'a> {
+    fn new(source: &'a str) -> Self {
+        Cursor {
+            source,
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.offset..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    // Empty span at the current position
+    fn here(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    // Consumes `prefix` if the remaining text starts with it
+    fn eat(&mut self, prefix: &str) -> bool {
+        if self.rest().starts_with(prefix) {
+            prefix.chars().for_each(|_| {
+                self.bump();
+            });
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Characters that end an atom
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')' || c == ';' || c == '"'
+}
+
+// Convert a string of characters into a list of tokens, skipping comments
+fn tokenize(string: &str, errors: &mut Vec<ParseError>) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut cursor = Cursor::new(string);
+
+    while let Some(c) = cursor.peek() {
+        let start = cursor.here();
+
+        let kind = if c.is_whitespace() {
+            cursor.bump();
+            continue;
+        } else if c == ';' {
+            while cursor.peek().is_some_and(|c| c != '\n') {
+                cursor.bump();
+            }
+            continue;
+        } else if cursor.eat("#|") {
+            if !skip_block_comment(&mut cursor) {
+                let opening = Span {
+                    end: start.start + 2,
+                    ..start
+                };
+                errors.push(ParseError::new(
+                    ParseErrorKind::UnterminatedComment,
+                    opening,
+                ));
+            }
+            continue;
+        } else if cursor.eat("#;") {
+            TokenKind::DatumComment
+        } else if c == '(' {
+            cursor.bump();
+            TokenKind::Open
+        } else if c == ')' {
+            cursor.bump();
+            TokenKind::Close
+        } else if c == '"' {
+            cursor.bump();
+            TokenKind::String(read_string(&mut cursor, start, errors))
+        } else if c == '\'' || c == '`' || c == ',' {
+            cursor.bump();
+            TokenKind::Quote(match c {
+                '\'' => "quote",
+                '`' => "quasiquote",
+                _ if cursor.eat("@") => "unquote-splicing",
+                _ => "unquote",
+            })
+        } else {
+            while cursor.peek().is_some_and(|c| !is_delimiter(c)) {
+                cursor.bump();
+            }
+            TokenKind::Atom
+        };
+
+        let span = start.to(cursor.here());
+        tokens.push(Token {
+            kind,
+            text: string[span.start..span.end].to_string(),
+            span,
+        });
+    }
+
+    tokens
+}
+
+// Reads the body of a string literal up to and including the closing quote,
+// resolving escape sequences. `start` is the span of the opening quote.
+fn read_string(cursor: &mut Cursor, start: Span, errors: &mut Vec<ParseError>) -> String {
+    let mut value = String::new();
+    loop {
+        let escape_start = cursor.here();
+        match cursor.bump() {
+            None => {
+                let quote = Span {
+                    end: start.start + 1,
+                    ..start
+                };
+                errors.push(ParseError::new(ParseErrorKind::UnterminatedString, quote));
+                return value;
+            }
+            Some('"') => return value,
+            Some('\\') => {
+                let escaped = match cursor.bump() {
+                    Some('n') => Some('\n'),
+                    Some('t') => Some('\t'),
+                    Some('r') => Some('\r'),
+                    Some('0') => Some('\0'),
+                    Some('"') => Some('"'),
+                    Some('\\') => Some('\\'),
+                    Some('u') => read_unicode_escape(cursor),
+                    _ => None,
+                };
+                match escaped {
+                    Some(c) => value.push(c),
+                    None => {
+                        let span = escape_start.to(cursor.here());
+                        let escape = cursor.source[span.start..span.end].to_string();
+                        errors.push(ParseError::new(ParseErrorKind::InvalidEscape(escape), span));
+                    }
+                }
+            }
+            Some(c) => value.push(c),
+        }
+    }
+}
+
+// Reads the `{XXXX}` part of a `\u{XXXX}` escape
+fn read_unicode_escape(cursor: &mut Cursor) -> Option<char> {
+    if !cursor.eat("{") {
+        return None;
+    }
+    let mut digits = String::new();
+    while let Some(c) = cursor.peek() {
+        if c == '}' || c == '"' || digits.len() > 6 {
+            break;
+        }
+        digits.push(c);
+        cursor.bump();
+    }
+    if !cursor.eat("}") {
+        return None;
+    }
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(std::char::from_u32)
+}
+
+// Skips the body of a `#|` comment, which may contain nested block comments.
+// Returns false when the input ends before the comment is closed.
+fn skip_block_comment(cursor: &mut Cursor) -> bool {
+    let mut depth = 1;
+    while depth > 0 {
+        if cursor.eat("#|") {
+            depth += 1;
+        } else if cursor.eat("|#") {
+            depth -= 1;
+        } else if cursor.bump().is_none() {
+            return false;
+        }
+    }
+    true
+}
+
+// Read an expression from a sequence of tokens. Errors inside a list are
+// recorded in `errors` and the rest of the list is still read.
+fn read_from_tokens(
+    tokens: &mut Vec<Token>,
+    eof: Span,
+    errors: &mut Vec<ParseError>,
+) -> Result<Expression, ParseError> {
+    match tokens.first() {
+        None => return Err(ParseError::new(ParseErrorKind::UnexpectedEof, eof)),
+        Some(t) if t.kind == TokenKind::DatumComment => {
+            skip_datum_comment(tokens, eof, errors)?;
+            return read_from_tokens(tokens, eof, errors);
+        }
+        Some(_) => {}
     }
     let token = tokens.remove(0);
-    if token == "(" {
-        let mut ts: Vec<Expression> = Vec::new();
-        while tokens[0] != ")" {
-            ts.push(read_from_tokens(&mut tokens));
-        }
-        tokens.remove(0);
-        Expression::List(ts)
-    } else if token == ")" {
-        panic!("Syntax error");
-    } else {
-        atom(token.to_owned())
+    match token.kind {
+        TokenKind::Open => {
+            let mut ts: Vec<Expression> = Vec::new();
+            loop {
+                let result = match tokens.first() {
+                    None => {
+                        return Err(ParseError::new(ParseErrorKind::UnbalancedParen, token.span))
+                    }
+                    Some(t) if t.kind == TokenKind::Close => break,
+                    Some(t) if t.kind == TokenKind::DatumComment => {
+                        skip_datum_comment(tokens, eof, errors)
+                    }
+                    Some(_) => read_from_tokens(tokens, eof, errors).map(|e| ts.push(e)),
+                };
+                match result {
+                    Ok(()) => {}
+                    // Only the innermost unclosed list is worth reporting
+                    Err(error) if error.kind == ParseErrorKind::UnbalancedParen => {
+                        return Err(error)
+                    }
+                    Err(error) => errors.push(error),
+                }
+            }
+            let close = tokens.remove(0);
+            Ok(Expression::new(
+                ExpressionKind::List(ts),
+                token.span.to(close.span),
+            ))
+        }
+        TokenKind::Close => Err(ParseError::new(
+            ParseErrorKind::UnexpectedCloseParen,
+            token.span,
+        )),
+        TokenKind::String(value) => Ok(Expression::new(ExpressionKind::String(value), token.span)),
+        TokenKind::Atom => atom(token.text, token.span),
+        TokenKind::Quote(form) => {
+            if tokens.first().is_none_or(|t| t.kind == TokenKind::Close) {
+                return Err(ParseError::new(
+                    ParseErrorKind::EmptyQuote(token.text),
+                    token.span,
+                ));
+            }
+            let quoted = read_from_tokens(tokens, eof, errors)?;
+            let head = Expression::new(ExpressionKind::Symbol(form.to_string()), token.span);
+            let span = token.span.to(quoted.span);
+            Ok(Expression::new(
+                ExpressionKind::List(vec![head, quoted]),
+                span,
+            ))
+        }
+        TokenKind::DatumComment => unreachable!("datum comments are skipped above"),
+    }
+}
+
+// Drop a `#;` token along with the expression it comments out
+fn skip_datum_comment(
+    tokens: &mut Vec<Token>,
+    eof: Span,
+    errors: &mut Vec<ParseError>,
+) -> Result<(), ParseError> {
+    let comment = tokens.remove(0);
+    match tokens.first() {
+        Some(t) if t.kind != TokenKind::Close => read_from_tokens(tokens, eof, errors).map(|_| ()),
+        _ => Err(ParseError::new(
+            ParseErrorKind::EmptyDatumComment,
+            comment.span,
+        )),
     }
 }
 
 // Select the appropiated atom type for the expression
-fn atom(token: String) -> Expression {
-    if let Ok(i) = str::parse::<i32>(&token) {
-        return Expression::Integer(i);
+fn atom(text: String, span: Span) -> Result<Expression, ParseError> {
+    let kind = if let Some(b) = boolean(&text) {
+        ExpressionKind::Boolean(b)
+    } else if let Some((value, ty)) = integer(&text) {
+        if !ty.unwrap_or(IntType::I64).fits(value) {
+            let ty = ty.unwrap_or(IntType::I64);
+            return Err(ParseError::new(
+                ParseErrorKind::IntegerOutOfRange(text, ty),
+                span,
+            ));
+        }
+        ExpressionKind::Integer(value as i64, ty)
+    } else if looks_numeric(&text) {
+        match str::parse::<f64>(&text) {
+            Ok(f) => ExpressionKind::Float(f),
+            Err(_) => return Err(ParseError::new(ParseErrorKind::InvalidLiteral(text), span)),
+        }
+    } else {
+        ExpressionKind::Symbol(text)
+    };
+    Ok(Expression::new(kind, span))
+}
+
+// Reads an integer literal: an optional sign, an optional `0x`, `0o` or `0b`
+// radix prefix, the digits and an optional type suffix such as `i8` or `u64`
+fn integer(text: &str) -> Option<(i128, Option<IntType>)> {
+    let (negative, rest) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let (rest, ty) = match IntType::ALL.iter().find(|ty| rest.ends_with(ty.name())) {
+        Some(ty) => (&rest[..rest.len() - ty.name().len()], Some(*ty)),
+        None => (rest, None),
+    };
+    let (digits, radix) = if let Some(digits) = rest.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = rest.strip_prefix("0o") {
+        (digits, 8)
+    } else if let Some(digits) = rest.strip_prefix("0b") {
+        (digits, 2)
+    } else {
+        (rest, 10)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
     }
-    if let Ok(f) = str::parse::<f32>(&token) {
-        return Expression::Float(f);
+    // Too long even for an i128 is certainly out of range
+    let value = i128::from_str_radix(digits, radix).unwrap_or(i128::MAX);
+    Some((if negative { -value } else { value }, ty))
+}
+
+fn boolean(text: &str) -> Option<bool> {
+    match text {
+        "#t" | "true" => Some(true),
+        "#f" | "false" => Some(false),
+        _ => None,
     }
-    Expression::Symbol(token)
+}
+
+// Tokens starting with a digit, optionally after a sign or a dot, are meant
+// to be numbers and must not silently become symbols
+fn looks_numeric(text: &str) -> bool {
+    let digits = text.trim_start_matches(['+', '-']);
+    let digits = digits.strip_prefix('.').unwrap_or(digits);
+    digits.starts_with(|c: char| c.is_ascii_digit())
 }
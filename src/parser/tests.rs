@@ -0,0 +1,343 @@
+use super::*;
+
+#[test]
+fn tokenize_tracks_offsets_lines_and_columns() {
+    let tokens = tokenize("(def\n  foo)", &mut Vec::new());
+    let spans: Vec<(&str, usize, usize, usize, usize)> = tokens
+        .iter()
+        .map(|t| {
+            (
+                t.text.as_str(),
+                t.span.start,
+                t.span.end,
+                t.span.line,
+                t.span.column,
+            )
+        })
+        .collect();
+    assert_eq!(
+        spans,
+        vec![
+            ("(", 0, 1, 1, 1),
+            ("def", 1, 4, 1, 2),
+            ("foo", 7, 10, 2, 3),
+            (")", 10, 11, 2, 6),
+        ]
+    );
+}
+
+// The only form of a program, unwrapped from the implicit module
+fn parse_form(program: &str) -> Expression {
+    match parse(program).unwrap().kind {
+        ExpressionKind::List(mut items) if items.len() == 2 => items.remove(1),
+        kind => panic!("expected a single form, got {:?}", kind),
+    }
+}
+
+#[test]
+fn parse_attaches_spans_to_every_expression() {
+    let ast = parse_form("(plus-two\n  1 (f 2))");
+    assert_eq!(ast.span.start, 0);
+    assert_eq!(ast.span.end, 20);
+    if let ExpressionKind::List(items) = &ast.kind {
+        assert_eq!(items[1].span.line, 2);
+        assert_eq!(items[1].span.column, 3);
+        assert_eq!(items[2].span.start, 14);
+        assert_eq!(items[2].span.end, 19);
+    } else {
+        panic!("expected a list");
+    }
+}
+
+fn parse_error(program: &str) -> (ParseErrorKind, usize, usize) {
+    let error = parse(program).unwrap_err().remove(0);
+    (error.kind, error.span.line, error.span.column)
+}
+
+#[test]
+fn parse_reports_unbalanced_paren() {
+    assert_eq!(
+        parse_error("(module\n  (def f (a) a)"),
+        (ParseErrorKind::UnbalancedParen, 1, 1)
+    );
+}
+
+#[test]
+fn parse_reports_stray_close_paren() {
+    assert_eq!(
+        parse_error(")"),
+        (ParseErrorKind::UnexpectedCloseParen, 1, 1)
+    );
+}
+
+#[test]
+fn parse_reports_unexpected_eof() {
+    assert_eq!(parse_error("  \n "), (ParseErrorKind::UnexpectedEof, 2, 2));
+}
+
+#[test]
+fn parse_reports_invalid_literal() {
+    assert_eq!(
+        parse_error("(f 12abc)"),
+        (ParseErrorKind::InvalidLiteral("12abc".to_string()), 1, 4)
+    );
+}
+
+#[test]
+fn parse_recovers_and_reports_every_error() {
+    let errors = parse(") (module (def f (a) 1x) (def g () 2y))").unwrap_err();
+    let kinds: Vec<ParseErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ParseErrorKind::UnexpectedCloseParen,
+            ParseErrorKind::InvalidLiteral("1x".to_string()),
+            ParseErrorKind::InvalidLiteral("2y".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn parse_reports_only_the_innermost_unclosed_paren() {
+    let errors = parse("(module (def f (a) (g 1x)").unwrap_err();
+    let kinds: Vec<ParseErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ParseErrorKind::InvalidLiteral("1x".to_string()),
+            ParseErrorKind::UnbalancedParen,
+        ]
+    );
+}
+
+fn symbols(expression: &Expression) -> Vec<String> {
+    match &expression.kind {
+        ExpressionKind::List(items) => items.iter().flat_map(symbols).collect(),
+        ExpressionKind::Symbol(name) => vec![name.to_string()],
+        _ => vec![],
+    }
+}
+
+#[test]
+fn parse_skips_comments_and_keeps_spans() {
+    let program = "; leading comment
+(module #| block #| nested |# still comment |#
+  (def f (a) ; trailing
+    #;(ignored expression) a))";
+    let ast = parse(program).unwrap();
+    assert_eq!(symbols(&ast), vec!["module", "def", "f", "a", "a"]);
+
+    if let ExpressionKind::List(items) = &ast.kind {
+        assert_eq!(items[1].span.line, 3);
+        assert_eq!(items[1].span.column, 3);
+        let text = &program[items[1].span.start..items[1].span.end];
+        assert!(text.starts_with("(def f (a) ; trailing\n"));
+        assert!(text.ends_with("#;(ignored expression) a)"));
+    } else {
+        panic!("expected a list");
+    }
+}
+
+#[test]
+fn parse_skips_stacked_datum_comments() {
+    let ast = parse_form("(f #; #; a b c)");
+    assert_eq!(symbols(&ast), vec!["f", "c"]);
+}
+
+#[test]
+fn parse_skips_trailing_datum_comments() {
+    // After the last form of the program
+    let ast = parse("(def f () 1) #; (foo)").unwrap();
+    if let ExpressionKind::List(items) = &ast.kind {
+        assert_eq!(items.len(), 2);
+        assert_eq!(symbols(&items[1]), vec!["def", "f"]);
+    } else {
+        panic!("expected a list");
+    }
+
+    // After the last item of a list
+    let ast = parse_form("(f a #; (b))");
+    assert_eq!(symbols(&ast), vec!["f", "a"]);
+}
+
+#[test]
+fn parse_reports_comment_errors() {
+    assert_eq!(
+        parse_error("(f) #| never closed"),
+        (ParseErrorKind::UnterminatedComment, 1, 5)
+    );
+    assert_eq!(
+        parse_error("(f a #;)"),
+        (ParseErrorKind::EmptyDatumComment, 1, 6)
+    );
+}
+
+#[test]
+fn parse_string_literals_with_escapes() {
+    let ast = parse_form(r#"(print "a (b) ; c\n\t\"\\\u{1F600}")"#);
+    if let ExpressionKind::List(items) = &ast.kind {
+        if let ExpressionKind::String(value) = &items[1].kind {
+            assert_eq!(value, "a (b) ; c\n\t\"\\\u{1F600}");
+        } else {
+            panic!("expected a string");
+        }
+        assert_eq!(items[1].span.start, 7);
+        assert_eq!(items[1].span.end, 35);
+    } else {
+        panic!("expected a list");
+    }
+}
+
+#[test]
+fn parse_boolean_literals() {
+    let ast = parse_form("(f #t #f true false #true)");
+    let kinds: Vec<String> = match &ast.kind {
+        ExpressionKind::List(items) => items.iter().map(|e| format!("{:?}", e.kind)).collect(),
+        _ => panic!("expected a list"),
+    };
+    assert_eq!(
+        kinds,
+        vec![
+            r#"Symbol("f")"#,
+            "Boolean(true)",
+            "Boolean(false)",
+            "Boolean(true)",
+            "Boolean(false)",
+            r##"Symbol("#true")"##,
+        ]
+    );
+}
+
+#[test]
+fn parse_float_literals_but_not_float_like_symbols() {
+    let ast = parse_form("(f 1.5 -.25 2e3 inf nan)");
+    let kinds: Vec<String> = match &ast.kind {
+        ExpressionKind::List(items) => items.iter().map(|e| format!("{:?}", e.kind)).collect(),
+        _ => panic!("expected a list"),
+    };
+    assert_eq!(
+        kinds,
+        vec![
+            r#"Symbol("f")"#,
+            "Float(1.5)",
+            "Float(-0.25)",
+            "Float(2000.0)",
+            r#"Symbol("inf")"#,
+            r#"Symbol("nan")"#,
+        ]
+    );
+}
+
+#[test]
+fn parse_integer_literals_with_radix_and_suffix() {
+    let ast = parse_form("(f 42 -7i8 0xffu64 0b101 0o17i32 -0x8000i16 18446744073709551615u64)");
+    let kinds: Vec<String> = match &ast.kind {
+        ExpressionKind::List(items) => items[1..].iter().map(|e| format!("{:?}", e.kind)).collect(),
+        _ => panic!("expected a list"),
+    };
+    assert_eq!(
+        kinds,
+        vec![
+            "Integer(42, None)",
+            "Integer(-7, Some(I8))",
+            "Integer(255, Some(U64))",
+            "Integer(5, None)",
+            "Integer(15, Some(I32))",
+            "Integer(-32768, Some(I16))",
+            "Integer(-1, Some(U64))",
+        ]
+    );
+}
+
+#[test]
+fn parse_reports_integers_out_of_range() {
+    assert_eq!(
+        parse_error("(f 128i8)"),
+        (
+            ParseErrorKind::IntegerOutOfRange("128i8".to_string(), IntType::I8),
+            1,
+            4
+        )
+    );
+    assert_eq!(
+        parse_error("(f -1u64)"),
+        (
+            ParseErrorKind::IntegerOutOfRange("-1u64".to_string(), IntType::U64),
+            1,
+            4
+        )
+    );
+    assert_eq!(
+        parse_error("(f 0x8000000000000000)"),
+        (
+            ParseErrorKind::IntegerOutOfRange("0x8000000000000000".to_string(), IntType::I64),
+            1,
+            4
+        )
+    );
+    assert_eq!(
+        parse_error("(f 0xfg)"),
+        (ParseErrorKind::InvalidLiteral("0xfg".to_string()), 1, 4)
+    );
+}
+
+#[test]
+fn parse_quote_shorthands_as_forms() {
+    let ast = parse_form("`(f ,x ,@xs 'y)");
+    assert_eq!(
+        symbols(&ast),
+        vec![
+            "quasiquote",
+            "f",
+            "unquote",
+            "x",
+            "unquote-splicing",
+            "xs",
+            "quote",
+            "y"
+        ]
+    );
+    assert_eq!((ast.span.start, ast.span.end), (0, 15));
+    assert_eq!(
+        parse_error("(f ')"),
+        (ParseErrorKind::EmptyQuote("'".to_string()), 1, 4)
+    );
+}
+
+#[test]
+fn parse_reports_string_errors() {
+    assert_eq!(
+        parse_error(r#"(print "bad \q escape")"#),
+        (ParseErrorKind::InvalidEscape(r"\q".to_string()), 1, 13)
+    );
+    assert_eq!(
+        parse_error("(print \"never closed)"),
+        (ParseErrorKind::UnterminatedString, 1, 8)
+    );
+}
+
+#[test]
+fn parse_wraps_top_level_forms_in_a_module() {
+    let program = "(def f (a) (g a))\n; comment\n(def main () (f 1))\n";
+    let ast = parse(program).unwrap();
+    assert_eq!(
+        symbols(&ast),
+        vec!["module", "def", "f", "a", "g", "a", "def", "main", "f"]
+    );
+    assert_eq!(ast.span.start, 0);
+    assert_eq!(ast.span.end, program.len());
+}
+
+#[test]
+fn parse_keeps_an_explicit_module() {
+    let ast = parse("(module (def main () (f 1)))").unwrap();
+    assert_eq!(symbols(&ast), vec!["module", "def", "main", "f"]);
+}
+
+#[test]
+fn parse_reports_trailing_garbage() {
+    assert_eq!(
+        parse_error("(def main () (f 1)))"),
+        (ParseErrorKind::UnexpectedCloseParen, 1, 20)
+    );
+}